@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{request_info, Caller};
+use crate::middleware::{ThreadRequest, ThreadState};
+
+/// `/api/cache/list`.
+/// Under tenant isolation, callers only see states of their own partitions.
+#[handler]
+pub async fn list(depot: &mut Depot, res: &mut Response) {
+    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
+    let caller = Caller::new(depot);
+
+    let (list_sender, list_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::CacheList(list_sender));
+    match list_receiver.recv_async().await {
+        Ok(mut items) => {
+            if info.reload.cache.tenant_isolation {
+                items.retain(|item| item.tenant == caller.tenant);
+            }
+            res.render(Json(items))
+        }
+        Err(_) => res
+            .status_code(StatusCode::SERVICE_UNAVAILABLE)
+            .render("model is not loaded"),
+    };
+}
+
+/// `/api/cache/stats`.
+#[handler]
+pub async fn stats(depot: &mut Depot, res: &mut Response) {
+    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+    let (stats_sender, stats_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::CacheStats(stats_sender));
+    match stats_receiver.recv_async().await {
+        Ok(report) => res.render(Json(report)),
+        Err(_) => res
+            .status_code(StatusCode::SERVICE_UNAVAILABLE)
+            .render("model is not loaded"),
+    };
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct FlushRequest {
+    /// Only states that start with this text are removed. All states are removed if not set.
+    prefix: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct FlushResponse {
+    /// Number of removed states.
+    count: usize,
+}
+
+/// `/api/cache/flush`.
+#[handler]
+pub async fn flush(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+    // an empty body flushes everything
+    let FlushRequest { prefix } = req.parse_json().await.unwrap_or_default();
+
+    let (count_sender, count_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::CacheFlush {
+        prefix,
+        sender: count_sender,
+    });
+    match count_receiver.recv_async().await {
+        Ok(count) => res.render(Json(FlushResponse { count })),
+        Err(_) => res
+            .status_code(StatusCode::SERVICE_UNAVAILABLE)
+            .render("failed to flush cache"),
+    };
+}
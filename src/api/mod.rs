@@ -2,18 +2,101 @@ use std::time::Duration;
 
 use anyhow::Result;
 use flume::Sender;
+use salvo::{
+    http::header::{HeaderValue, RETRY_AFTER},
+    jwt_auth::JwtAuthDepotExt,
+    prelude::*,
+    sse::SseEvent,
+};
 
 pub mod adapter;
 pub mod auth;
+pub mod cache;
 pub mod file;
 pub mod model;
 pub mod oai;
+pub mod request;
+pub mod session;
+pub mod states;
 
 pub use adapter::adapters;
 pub use file::{dir, load_config, models, save_config, unzip};
 pub use model::{info, load, save, state, unload};
 
-use crate::middleware::{RuntimeInfo, ThreadRequest};
+use crate::{
+    config::{ListenerOption, Scheduler},
+    middleware::{GenerateRequest, Priority, RuntimeInfo, ThreadRequest, ThreadState},
+    JwtClaims,
+};
+
+/// Who sends a request, and how the requests of the caller are scheduled.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// The `sid` of the caller's token, if any.
+    pub tenant: Option<String>,
+    pub priority: Priority,
+    pub weight: usize,
+}
+
+impl Caller {
+    /// Identify the caller by its token, and look up the scheduling options of its app key.
+    pub fn new(depot: &Depot) -> Self {
+        let tenant = depot
+            .jwt_auth_data::<JwtClaims>()
+            .map(|data| data.claims.sid.clone());
+        let key = depot
+            .get::<ListenerOption>("listen")
+            .ok()
+            .and_then(|listen| {
+                listen
+                    .app_keys
+                    .iter()
+                    .find(|key| Some(&key.app_id) == tenant.as_ref())
+                    .cloned()
+            });
+        let (priority, weight) = match key {
+            Some(key) => (key.priority, key.weight.max(1)),
+            None => (Priority::default(), 1),
+        };
+        Self {
+            tenant,
+            priority,
+            weight,
+        }
+    }
+
+    /// Attach the caller to a request. A priority set by the request itself takes precedence over that of the key.
+    pub fn apply(&self, request: GenerateRequest) -> GenerateRequest {
+        GenerateRequest {
+            tenant: self.tenant.clone(),
+            priority: request.priority.or(Some(self.priority)),
+            weight: self.weight,
+            ..request
+        }
+    }
+}
+
+/// Reject a request with `429 Too Many Requests` if the queue is full. Returns whether the request is rejected.
+pub fn reject_overload(runtime: &RuntimeInfo, res: &mut Response) -> bool {
+    let Scheduler {
+        max_queue_len,
+        retry_after,
+        ..
+    } = runtime.reload.scheduler;
+    if runtime.queue_len < max_queue_len {
+        return false;
+    }
+    res.status_code(StatusCode::TOO_MANY_REQUESTS);
+    res.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    res.render("too many requests in the queue");
+    true
+}
+
+/// An SSE comment that tells the client where a choice waits in the queue. Clients of the OpenAI API ignore it.
+pub fn queued_event(index: usize, position: usize) -> SseEvent {
+    SseEvent::default().comment(format!("choice {index} is queued at position {position}"))
+}
 
 pub async fn try_request_info(sender: Sender<ThreadRequest>) -> Result<RuntimeInfo> {
     let (info_sender, info_receiver) = flume::unbounded();
@@ -31,6 +114,25 @@ pub async fn request_info(sender: Sender<ThreadRequest>, sleep: Duration) -> Run
     }
 }
 
+/// Find the thread of the model that a request selects by name, along with its runtime info.
+/// Requests that do not name a model go to the main one.
+pub async fn route_model(
+    depot: &Depot,
+    name: Option<&str>,
+) -> Option<(Sender<ThreadRequest>, RuntimeInfo)> {
+    let ThreadState { sender, names, .. } = depot.obtain::<ThreadState>().unwrap();
+    let name = match name {
+        Some(name) if !name.is_empty() => name,
+        _ => {
+            let runtime = request_info(sender.to_owned(), Duration::from_secs(1)).await;
+            return Some((sender.to_owned(), runtime));
+        }
+    };
+    let sender = names.read().await.get(name).cloned()?;
+    let runtime = try_request_info(sender.clone()).await.ok()?;
+    Some((sender, runtime))
+}
+
 pub async fn request_info_stream(
     sender: Sender<ThreadRequest>,
     info_sender: Sender<RuntimeInfo>,
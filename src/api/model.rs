@@ -47,7 +47,9 @@ pub async fn state(depot: &mut Depot, res: &mut Response) {
 /// `/api/models/load`.
 #[handler]
 pub async fn load(depot: &mut Depot, req: &mut Request) -> StatusCode {
-    let ThreadState { sender, model_path } = depot.obtain::<ThreadState>().unwrap();
+    let ThreadState {
+        sender, model_path, ..
+    } = depot.obtain::<ThreadState>().unwrap();
     let (result_sender, result_receiver) = flume::unbounded();
     let mut request: ReloadRequest = req.parse_body().await.unwrap();
 
@@ -62,6 +64,12 @@ pub async fn load(depot: &mut Depot, req: &mut Request) -> StatusCode {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+    for init in request.state.iter_mut() {
+        init.path = match build_path(model_path, &init.path) {
+            Ok(path) => path,
+            Err(_) => return StatusCode::NOT_FOUND,
+        }
+    }
 
     let _ = sender.send(ThreadRequest::Reload {
         request: Box::new(request),
@@ -76,7 +84,9 @@ pub async fn load(depot: &mut Depot, req: &mut Request) -> StatusCode {
 /// `/api/models/save`.
 #[handler]
 pub async fn save(depot: &mut Depot, req: &mut Request) -> StatusCode {
-    let ThreadState { sender, model_path } = depot.obtain::<ThreadState>().unwrap();
+    let ThreadState {
+        sender, model_path, ..
+    } = depot.obtain::<ThreadState>().unwrap();
     let (result_sender, result_receiver) = flume::unbounded();
     let mut request: SaveRequest = req.parse_body().await.unwrap();
 
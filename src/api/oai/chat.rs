@@ -1,19 +1,27 @@
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use futures_util::StreamExt;
+use flume::Receiver;
+use futures_util::{future::join_all, stream, stream::select_all, StreamExt};
 use itertools::Itertools;
 use regex::Regex;
 use salvo::{oapi::extract::JsonBody, prelude::*, sse::SseEvent, Depot, Writer};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
-use super::*;
+use super::{
+    tool::{
+        render_tool_calls, render_tool_result, render_tools, PartialToolCall, Tool, ToolCall,
+        ToolCallParser, ToolChoice,
+    },
+    *,
+};
 use crate::{
-    api::request_info,
+    api::{queued_event, reject_overload, route_model, Caller},
     middleware::{
-        Array, FinishReason, GenerateRequest, ThreadRequest, ThreadState, Token, TokenCounter,
-        MAX_TOKENS,
+        Array, FinishReason, GenerateRequest, Priority, Token, TokenCounter, TokenLogprob,
+        MAX_TOKENS, MAX_TOP_LOGPROBS,
     },
+    run::request_id,
     sampler::Sampler,
 };
 
@@ -26,6 +34,8 @@ pub enum Role {
     User,
     #[serde(alias = "assistant")]
     Assistant,
+    #[serde(alias = "tool")]
+    Tool,
 }
 
 impl std::fmt::Display for Role {
@@ -34,6 +44,7 @@ impl std::fmt::Display for Role {
             Role::System => write!(f, "System"),
             Role::User => write!(f, "User"),
             Role::Assistant => write!(f, "Assistant"),
+            Role::Tool => write!(f, "Tool"),
         }
     }
 }
@@ -41,10 +52,15 @@ impl std::fmt::Display for Role {
 #[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ChatRecord {
     role: Role,
-    content: String,
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize, ToSchema)]
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct ChatRequest {
     #[serde(default)]
     messages: Array<ChatRecord>,
@@ -56,9 +72,31 @@ pub struct ChatRequest {
     stop: Array<String>,
     #[serde(default)]
     stream: bool,
+    #[serde(default = "default_n")]
+    n: usize,
+    #[serde(default)]
+    logprobs: bool,
+    #[serde(default)]
+    top_logprobs: usize,
+    #[serde(default)]
+    response_format: ResponseFormat,
+    #[serde(default)]
+    tools: Vec<Tool>,
+    #[serde(default)]
+    tool_choice: ToolChoice,
     #[serde(default)]
     #[serde(alias = "logit_bias")]
     bias: HashMap<u16, f32>,
+    #[serde(default)]
+    state: Option<String>,
+    /// Name of the hosted model to run. Falls back to the main model.
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    priority: Option<Priority>,
+    /// Seconds that the request may take. Falls back to the server default.
+    #[serde(default)]
+    timeout: Option<f64>,
     #[serde(flatten)]
     sampler: SamplerParams,
 }
@@ -71,8 +109,18 @@ impl Default for ChatRequest {
             max_tokens: 256,
             stop: Array::Item("\n\n".into()),
             stream: false,
+            n: 1,
+            logprobs: false,
+            top_logprobs: 0,
+            response_format: ResponseFormat::default(),
+            tools: Vec::new(),
+            tool_choice: ToolChoice::default(),
             bias: HashMap::new(),
             sampler: Default::default(),
+            state: None,
+            model: None,
+            priority: None,
+            timeout: None,
         }
     }
 }
@@ -85,8 +133,24 @@ fn default_stop() -> Array<String> {
     ChatRequest::default().stop
 }
 
+impl ChatRequest {
+    fn use_tools(&self) -> bool {
+        !self.tools.is_empty() && !self.tool_choice.is_none()
+    }
+
+    /// Text that the reply of the assistant is forced to begin with.
+    fn prefix(&self) -> String {
+        match self.use_tools() {
+            true => self.tool_choice.prefix(),
+            false => String::new(),
+        }
+    }
+}
+
 impl From<ChatRequest> for GenerateRequest {
     fn from(value: ChatRequest) -> Self {
+        let use_tools = value.use_tools();
+        let prefix = value.prefix();
         let ChatRequest {
             messages,
             names,
@@ -94,23 +158,55 @@ impl From<ChatRequest> for GenerateRequest {
             stop,
             sampler,
             bias,
+            logprobs,
+            top_logprobs,
+            response_format,
+            state,
+            priority,
+            timeout,
+            tools,
             ..
         } = value;
 
+        let messages = Vec::from(messages).into_iter().map(|record| {
+            let ChatRecord {
+                role,
+                content,
+                tool_calls,
+                tool_call_id,
+            } = record;
+            let content = match tool_call_id {
+                Some(id) => render_tool_result(&id, &content.unwrap_or_default()),
+                None => content.unwrap_or_default(),
+            };
+            let content = [content, render_tool_calls(&tool_calls)]
+                .into_iter()
+                .filter(|text| !text.is_empty())
+                .join("\n");
+            (role, content)
+        });
+        let messages = match use_tools {
+            true => [(Role::System, render_tools(&tools))]
+                .into_iter()
+                .chain(messages)
+                .collect_vec(),
+            false => messages.collect(),
+        };
+
         let re = Regex::new(r"\n(\s*\n)+").unwrap();
-        let prompt = Vec::from(messages.clone())
-            .into_iter()
-            .map(|ChatRecord { role, content }| {
-                let role = names.get(&role).cloned().unwrap_or(role.to_string());
-                let content = re.replace_all(&content, "\n");
+        let prompt = messages
+            .iter()
+            .map(|(role, content)| {
+                let role = names.get(role).cloned().unwrap_or(role.to_string());
+                let content = re.replace_all(content, "\n");
                 let content = content.trim();
                 format!("{role}: {content}")
             })
             .join("\n\n");
-        let model_text = Vec::from(messages)
+        let model_text = messages
             .into_iter()
-            .filter(|record| record.role == Role::Assistant)
-            .map(|record| record.content)
+            .filter(|(role, _)| *role == Role::Assistant)
+            .map(|(_, content)| content)
             .join("\n\n");
 
         let assistant = Role::Assistant;
@@ -118,11 +214,15 @@ impl From<ChatRequest> for GenerateRequest {
             .get(&assistant)
             .cloned()
             .unwrap_or(assistant.to_string());
-        let prompt = prompt + &format!("\n\n{assistant}:");
+        let prompt = match prefix.is_empty() {
+            true => prompt + &format!("\n\n{assistant}:"),
+            false => prompt + &format!("\n\n{assistant}: {prefix}"),
+        };
 
         let max_tokens = max_tokens.min(MAX_TOKENS);
         let stop = stop.into();
         let bias = Arc::new(bias);
+        let logprobs = logprobs.then_some(top_logprobs.min(MAX_TOP_LOGPROBS));
         let sampler: Arc<RwLock<dyn Sampler + Send + Sync>> = sampler.into();
 
         Self {
@@ -132,22 +232,37 @@ impl From<ChatRequest> for GenerateRequest {
             stop,
             sampler,
             bias,
+            logprobs,
+            grammar: response_format.into(),
+            state,
+            priority,
+            timeout: timeout.and_then(|timeout| Duration::try_from_secs_f64(timeout).ok()),
             ..Default::default()
         }
     }
 }
 
+#[derive(Debug, Default, Clone, Serialize, ToSchema, ToResponse)]
+struct ChatLogprobs {
+    content: Vec<TokenLogprob>,
+}
+
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
 struct ChatChoice {
     message: ChatRecord,
     index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<ChatLogprobs>,
     finish_reason: FinishReason,
 }
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
 struct ChatResponse {
+    /// ID to cancel the request by, shared by all choices.
+    id: String,
     object: String,
     model: String,
+    system_fingerprint: String,
     choices: Vec<ChatChoice>,
     #[serde(rename = "usage")]
     counter: TokenCounter,
@@ -161,46 +276,57 @@ enum PartialChatRecord {
     None,
     Role(Role),
     Content(String),
+    ToolCalls(Vec<PartialToolCall>),
 }
 
 #[derive(Debug, Default, Serialize, ToSchema, ToResponse)]
 struct PartialChatChoice {
     delta: PartialChatRecord,
     index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<ChatLogprobs>,
     finish_reason: FinishReason,
 }
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
 struct PartialChatResponse {
+    /// ID to cancel the request by, shared by all choices.
+    id: String,
     object: String,
     model: String,
+    system_fingerprint: String,
     choices: Vec<PartialChatChoice>,
 }
 
-async fn respond_one(depot: &mut Depot, request: ChatRequest, res: &mut Response) {
-    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
-
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
+fn take_logprobs(logprobs: &mut Vec<TokenLogprob>) -> Option<ChatLogprobs> {
+    match logprobs.is_empty() {
+        true => None,
+        false => Some(ChatLogprobs {
+            content: std::mem::take(logprobs),
+        }),
+    }
+}
 
+/// Collect the tokens of one choice until it stops, and parse tool calls out of its text.
+async fn collect_choice(
+    index: usize,
+    receiver: Receiver<Token>,
+    prefix: &str,
+    logprobs: bool,
+) -> (ChatChoice, TokenCounter) {
     let mut token_counter = TokenCounter::default();
     let mut finish_reason = FinishReason::Null;
     let mut text = String::new();
-    let mut stream = token_receiver.into_stream();
+    let mut content = vec![];
+    let mut stream = receiver.into_stream();
 
     while let Some(token) = stream.next().await {
         match token {
-            Token::Start => {}
+            Token::Queued(_) | Token::Start => {}
             Token::Content(token) => {
                 text += &token;
             }
+            Token::Logprob(logprob) => content.push(logprob),
             Token::Stop(reason, counter) => {
                 finish_reason = reason;
                 token_counter = counter;
@@ -210,69 +336,233 @@ async fn respond_one(depot: &mut Depot, request: ChatRequest, res: &mut Response
         }
     }
 
+    let logprobs = logprobs.then_some(ChatLogprobs { content });
+
+    let mut parser = ToolCallParser::new(prefix);
+    let (mut text, mut tool_calls) = parser.feed(&text);
+    let (rest, rest_calls) = parser.finish();
+    text += &rest;
+    tool_calls.extend(rest_calls);
+
+    let finish_reason = match tool_calls.is_empty() {
+        true => finish_reason,
+        false => FinishReason::ToolCalls,
+    };
+    let text = text.trim();
+    let content = (!text.is_empty() || tool_calls.is_empty()).then(|| text.into());
+
+    let choice = ChatChoice {
+        message: ChatRecord {
+            role: Role::Assistant,
+            content,
+            tool_calls,
+            tool_call_id: None,
+        },
+        index,
+        logprobs,
+        finish_reason,
+    };
+    (choice, token_counter)
+}
+
+async fn respond_one(depot: &mut Depot, request: ChatRequest, res: &mut Response) {
+    let Some((sender, info)) = route_model(depot, request.model.as_deref()).await else {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("model not found");
+        return;
+    };
+    let model_name = info.reload.name();
+    let fingerprint = info.reload.fingerprint();
+    let caller = Caller::new(depot);
+    if !info.has_state(request.state.as_deref(), caller.tenant.as_deref()) {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("initial state not found");
+        return;
+    }
+    if reject_overload(&info, res) {
+        return;
+    }
+
+    let logprobs = request.logprobs;
+    let prefix = request.prefix();
+    let id = request_id();
+    let requests: Vec<GenerateRequest> = (0..request.n.max(1))
+        .map(|index| {
+            ChatRequest {
+                sampler: request.sampler.choice(index),
+                ..request.clone()
+            }
+            .into()
+        })
+        .map(|request| GenerateRequest {
+            id: Some(id.clone()),
+            ..caller.apply(request)
+        })
+        .collect();
+    let receivers = request_choices(&sender, requests, info.tokenizer);
+
+    let choices = receivers
+        .into_iter()
+        .enumerate()
+        .map(|(index, receiver)| collect_choice(index, receiver, &prefix, logprobs));
+    let (choices, counters): (Vec<_>, Vec<_>) = join_all(choices).await.into_iter().unzip();
+
     let json = Json(ChatResponse {
+        id,
         object: "chat.completion".into(),
         model: model_name,
-        choices: vec![ChatChoice {
-            message: ChatRecord {
-                role: Role::Assistant,
-                content: text.trim().into(),
-            },
-            index: 0,
-            finish_reason,
-        }],
-        counter: token_counter,
+        system_fingerprint: fingerprint,
+        choices,
+        counter: TokenCounter::merge(counters),
     });
     res.render(json);
 }
 
 async fn respond_stream(depot: &mut Depot, request: ChatRequest, res: &mut Response) {
-    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
-
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
+    let Some((sender, info)) = route_model(depot, request.model.as_deref()).await else {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("model not found");
+        return;
+    };
+    let model_name = info.reload.name();
+    let fingerprint = info.reload.fingerprint();
+    let caller = Caller::new(depot);
+    if !info.has_state(request.state.as_deref(), caller.tenant.as_deref()) {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("initial state not found");
+        return;
+    }
+    if reject_overload(&info, res) {
+        return;
+    }
 
-    let mut start_token = true;
-    let stream = token_receiver.into_stream().map(move |token| {
-        let choice = match token {
-            Token::Start => PartialChatChoice {
+    let prefix = request.prefix();
+    let id = request_id();
+    let requests: Vec<GenerateRequest> = (0..request.n.max(1))
+        .map(|index| {
+            ChatRequest {
+                sampler: request.sampler.choice(index),
+                ..request.clone()
+            }
+            .into()
+        })
+        .map(|request| GenerateRequest {
+            id: Some(id.clone()),
+            ..caller.apply(request)
+        })
+        .collect();
+    let receivers = request_choices(&sender, requests, info.tokenizer);
+
+    let num_choices = receivers.len();
+    let mut start_token = vec![true; num_choices];
+    let mut logprobs: Vec<Vec<TokenLogprob>> = vec![vec![]; num_choices];
+    let mut parsers = vec![ToolCallParser::new(&prefix); num_choices];
+    let mut num_done = 0;
+    let stream = select_all(
+        receivers
+            .into_iter()
+            .enumerate()
+            .map(|(index, token_receiver)| {
+                token_receiver
+                    .into_stream()
+                    .map(move |token| (index, token))
+                    .boxed()
+            }),
+    )
+    .flat_map(move |(index, token)| {
+        let mut choices = vec![];
+        match token {
+            Token::Queued(position) => {
+                return stream::iter(vec![Ok(queued_event(index, position))]);
+            }
+            Token::Start => choices.push(PartialChatChoice {
                 delta: PartialChatRecord::Role(Role::Assistant),
+                index,
                 ..Default::default()
-            },
+            }),
             Token::Content(token) => {
-                let token = match start_token {
-                    true => token.trim_start().into(),
-                    false => token,
+                let (text, calls) = parsers[index].feed(&token);
+                let text = match start_token[index] {
+                    true => text.trim_start().into(),
+                    false => text,
                 };
-                start_token = false;
-                PartialChatChoice {
-                    delta: PartialChatRecord::Content(token),
+                if !text.is_empty() {
+                    start_token[index] = false;
+                    choices.push(PartialChatChoice {
+                        delta: PartialChatRecord::Content(text),
+                        index,
+                        logprobs: take_logprobs(&mut logprobs[index]),
+                        ..Default::default()
+                    });
+                }
+                if !calls.is_empty() {
+                    choices.push(PartialChatChoice {
+                        delta: PartialChatRecord::ToolCalls(parsers[index].index(calls)),
+                        index,
+                        logprobs: take_logprobs(&mut logprobs[index]),
+                        ..Default::default()
+                    });
+                }
+            }
+            Token::Logprob(logprob) => {
+                // log probabilities are sent along with the next chunk of content
+                logprobs[index].push(logprob);
+            }
+            Token::Stop(finish_reason, _) => {
+                // flush the text held back by the tool call parser
+                let (text, calls) = parsers[index].finish();
+                if !text.is_empty() {
+                    choices.push(PartialChatChoice {
+                        delta: PartialChatRecord::Content(text),
+                        index,
+                        ..Default::default()
+                    });
+                }
+                if !calls.is_empty() {
+                    choices.push(PartialChatChoice {
+                        delta: PartialChatRecord::ToolCalls(parsers[index].index(calls)),
+                        index,
+                        ..Default::default()
+                    });
+                }
+
+                let finish_reason = match parsers[index].num_calls() {
+                    0 => finish_reason,
+                    _ => FinishReason::ToolCalls,
+                };
+                choices.push(PartialChatChoice {
+                    index,
+                    logprobs: take_logprobs(&mut logprobs[index]),
+                    finish_reason,
                     ..Default::default()
+                });
+            }
+            Token::Done => {
+                // the stream is done only after all choices are done
+                num_done += 1;
+                if num_done == num_choices {
+                    return stream::iter(vec![Ok(SseEvent::default().text("[DONE]"))]);
                 }
             }
-            Token::Stop(finish_reason, _) => PartialChatChoice {
-                finish_reason,
-                ..Default::default()
-            },
-            Token::Done => return Ok(SseEvent::default().text("[DONE]")),
             _ => unreachable!(),
         };
 
-        match serde_json::to_string(&PartialChatResponse {
-            object: "chat.completion.chunk".into(),
-            model: model_name.clone(),
-            choices: vec![choice],
-        }) {
-            Ok(json_text) => Ok(SseEvent::default().text(json_text)),
-            Err(err) => Err(err),
-        }
+        let events = choices
+            .into_iter()
+            .map(|choice| {
+                match serde_json::to_string(&PartialChatResponse {
+                    id: id.clone(),
+                    object: "chat.completion.chunk".into(),
+                    model: model_name.clone(),
+                    system_fingerprint: fingerprint.clone(),
+                    choices: vec![choice],
+                }) {
+                    Ok(json_text) => Ok(SseEvent::default().text(json_text)),
+                    Err(err) => Err(err),
+                }
+            })
+            .collect_vec();
+        stream::iter(events)
     });
     salvo::sse::stream(res, stream);
 }
@@ -281,7 +571,9 @@ async fn respond_stream(depot: &mut Depot, request: ChatRequest, res: &mut Respo
 #[endpoint(
         responses(
             (status_code = 200, description = "Generate one response if `stream` is false.", body = ChatResponse),
-            (status_code = 201, description = "Generate SSE response if `stream` is true. `StatusCode` should be 200.", body = PartialChatResponse)
+            (status_code = 201, description = "Generate SSE response if `stream` is true. `StatusCode` should be 200.", body = PartialChatResponse),
+            (status_code = 404, description = "The model or the initial state is not found."),
+            (status_code = 429, description = "The queue is full. Retry after the seconds in `Retry-After`.")
         )
     )]
 pub async fn chat_completions(depot: &mut Depot, req: JsonBody<ChatRequest>, res: &mut Response) {
@@ -291,3 +583,35 @@ pub async fn chat_completions(depot: &mut Depot, req: JsonBody<ChatRequest>, res
         false => respond_one(depot, request, res).await,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queued() {
+        // the request waits behind a busy slot before it starts
+        let (sender, receiver) = flume::unbounded();
+        let counter = TokenCounter {
+            prompt_tokens: 3,
+            completion_tokens: 2,
+            total_tokens: 5,
+        };
+        for token in [
+            Token::Queued(2),
+            Token::Queued(1),
+            Token::Start,
+            Token::Content("Hello".into()),
+            Token::Content(" world".into()),
+            Token::Stop(FinishReason::Stop, counter),
+            Token::Done,
+        ] {
+            sender.send(token).unwrap();
+        }
+
+        let (choice, counter) = collect_choice(0, receiver, "", false).await;
+        assert_eq!(choice.message.content.as_deref(), Some("Hello world"));
+        assert!(matches!(choice.finish_reason, FinishReason::Stop));
+        assert_eq!(counter.total_tokens, 5);
+    }
+}
@@ -1,6 +1,7 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, future, sync::Arc, time::Duration};
 
-use futures_util::StreamExt;
+use futures_util::{future::join_all, stream::select_all, StreamExt};
+use itertools::Itertools;
 use salvo::{
     oapi::{extract::JsonBody, ToResponse, ToSchema},
     prelude::*,
@@ -11,39 +12,81 @@ use serde::{Deserialize, Serialize};
 
 use super::*;
 use crate::{
-    api::request_info,
+    api::{queued_event, reject_overload, route_model, Caller},
     middleware::{
-        Array, FinishReason, GenerateRequest, ThreadRequest, ThreadState, Token, TokenCounter,
-        MAX_TOKENS,
+        Array, FinishReason, GenerateRequest, Priority, Token, TokenCounter, TokenLogprob,
+        MAX_TOKENS, MAX_TOP_LOGPROBS,
     },
+    run::request_id,
 };
 
-#[derive(Debug, Deserialize, ToSchema, ToResponse)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum CompletionPrompt {
+    Text(Array<String>),
+    Tokens(Vec<u16>),
+    TokenArray(Vec<Vec<u16>>),
+}
+
+impl Default for CompletionPrompt {
+    fn default() -> Self {
+        Self::Text(Array::default())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema, ToResponse)]
 pub struct CompletionRequest {
     #[serde(default)]
-    prompt: Array<String>,
+    prompt: CompletionPrompt,
     #[serde(default = "default_max_tokens")]
     max_tokens: usize,
     #[serde(default)]
     stop: Array<String>,
     #[serde(default)]
     stream: bool,
+    #[serde(default = "default_n")]
+    n: usize,
+    #[serde(default)]
+    logprobs: Option<usize>,
+    #[serde(default)]
+    response_format: ResponseFormat,
     #[serde(default)]
     #[serde(alias = "logit_bias")]
     bias: HashMap<u16, f32>,
+    #[serde(default)]
+    state: Option<String>,
+    /// Name of the hosted model to run. Falls back to the main model.
+    #[serde(default)]
+    pub(crate) model: Option<String>,
+    #[serde(default)]
+    priority: Option<Priority>,
+    /// Seconds that the request may take. Falls back to the server default.
+    #[serde(default)]
+    timeout: Option<f64>,
     #[serde(flatten)]
     sampler: SamplerParams,
+    /// Set by the session API only.
+    #[serde(skip)]
+    pub(crate) session: Option<String>,
 }
 
 impl Default for CompletionRequest {
     fn default() -> Self {
         Self {
-            prompt: Array::default(),
+            prompt: CompletionPrompt::default(),
             max_tokens: 256,
             stop: Array::default(),
             stream: false,
+            n: 1,
+            logprobs: None,
+            response_format: ResponseFormat::default(),
             bias: HashMap::new(),
             sampler: Default::default(),
+            state: None,
+            model: None,
+            priority: None,
+            timeout: None,
+            session: None,
         }
     }
 }
@@ -52,6 +95,74 @@ fn default_max_tokens() -> usize {
     CompletionRequest::default().max_tokens
 }
 
+impl CompletionRequest {
+    /// Make sure that token ids in the prompt are within the vocabulary.
+    /// A session continues a single conversation, so its requests must make exactly one choice.
+    fn check_prompt(&self, tokenizer: &Tokenizer) -> Result<(), &'static str> {
+        let single =
+            self.n == 1 && !matches!(&self.prompt, CompletionPrompt::TokenArray(x) if x.len() > 1);
+        if self.session.is_some() && !single {
+            return Err("a session request takes one prompt with `n` of 1");
+        }
+
+        let prompts = match &self.prompt {
+            CompletionPrompt::Text(_) => return Ok(()),
+            CompletionPrompt::Tokens(tokens) => vec![tokens],
+            CompletionPrompt::TokenArray(prompts) if prompts.is_empty() => {
+                return Err("prompt is empty")
+            }
+            CompletionPrompt::TokenArray(prompts) => prompts.iter().collect(),
+        };
+        match prompts
+            .into_iter()
+            .all(|tokens| tokenizer.decode(tokens).is_ok())
+        {
+            true => Ok(()),
+            false => Err("prompt has tokens out of the vocabulary"),
+        }
+    }
+
+    /// Requests of all choices, grouped by prompt.
+    /// A batch of token prompts gets `n` choices for each prompt, indexed one prompt after another.
+    fn choices(&self, caller: &Caller, id: &str) -> Vec<Vec<GenerateRequest>> {
+        let prompts = match &self.prompt {
+            CompletionPrompt::TokenArray(prompts) => prompts
+                .iter()
+                .cloned()
+                .map(CompletionPrompt::Tokens)
+                .collect(),
+            prompt => vec![prompt.clone()],
+        };
+        prompts
+            .into_iter()
+            .map(|prompt| {
+                (0..self.n.max(1))
+                    .map(|index| {
+                        GenerateRequest::from(CompletionRequest {
+                            prompt: prompt.clone(),
+                            sampler: self.sampler.choice(index),
+                            ..self.clone()
+                        })
+                    })
+                    .map(|request| GenerateRequest {
+                        id: Some(id.to_string()),
+                        ..caller.apply(request)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Usage of all choices in the order of [`CompletionRequest::choices`].
+    /// Choices of the same prompt share it, while prompts of a batch add up.
+    fn usage(&self, counters: &[TokenCounter]) -> TokenCounter {
+        let merged = counters
+            .chunks(self.n.max(1))
+            .map(|counters| TokenCounter::merge(counters.iter().cloned()));
+        TokenCounter::sum(merged)
+    }
+}
+
 impl From<CompletionRequest> for GenerateRequest {
     fn from(value: CompletionRequest) -> Self {
         let CompletionRequest {
@@ -60,37 +171,95 @@ impl From<CompletionRequest> for GenerateRequest {
             stop,
             sampler,
             bias,
+            logprobs,
+            response_format,
+            state,
+            priority,
+            timeout,
+            session,
             ..
         } = value;
 
-        let prompt = Vec::from(prompt).join("");
+        let (prompt, prompt_tokens) = match prompt {
+            CompletionPrompt::Text(text) => (Vec::from(text).join(""), None),
+            CompletionPrompt::Tokens(tokens) => (String::new(), Some(tokens)),
+            // batches are split into one request per prompt beforehand
+            CompletionPrompt::TokenArray(tokens) => (String::new(), Some(tokens.concat())),
+        };
         let max_tokens = max_tokens.min(MAX_TOKENS);
         let stop = stop.into();
         let bias = Arc::new(bias);
+        let logprobs = logprobs.map(|top| top.min(MAX_TOP_LOGPROBS));
         let sampler = sampler.into();
 
         Self {
             prompt,
+            prompt_tokens,
             max_tokens,
             stop,
             sampler,
             bias,
+            logprobs,
+            grammar: response_format.into(),
+            state,
+            priority,
+            timeout: timeout.and_then(|timeout| Duration::try_from_secs_f64(timeout).ok()),
+            session,
             ..Default::default()
         }
     }
 }
 
+#[derive(Debug, Default, Clone, Serialize, ToSchema, ToResponse)]
+pub struct CompletionLogprobs {
+    tokens: Vec<String>,
+    token_logprobs: Vec<f32>,
+    top_logprobs: Vec<HashMap<String, f32>>,
+    text_offset: Vec<usize>,
+}
+
+impl CompletionLogprobs {
+    /// Convert log probabilities into the legacy completion format, with offsets counted from `offset`.
+    fn new(logprobs: Vec<TokenLogprob>, offset: usize) -> Self {
+        let mut result = Self::default();
+        let mut offset = offset;
+        for TokenLogprob {
+            token,
+            logprob,
+            top_logprobs,
+            ..
+        } in logprobs
+        {
+            let top_logprobs = top_logprobs
+                .into_iter()
+                .map(|top| (top.token, top.logprob))
+                .collect();
+            result.text_offset.push(offset);
+            offset += token.chars().count();
+            result.tokens.push(token);
+            result.token_logprobs.push(logprob);
+            result.top_logprobs.push(top_logprobs);
+        }
+        result
+    }
+}
+
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
 pub struct CompletionChoice {
     text: String,
     index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<CompletionLogprobs>,
     finish_reason: FinishReason,
 }
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
 pub struct CompletionResponse {
+    /// ID to cancel the request by, shared by all choices.
+    id: String,
     object: String,
     model: String,
+    system_fingerprint: String,
     choices: Vec<CompletionChoice>,
     #[serde(rename = "usage")]
     counter: TokenCounter,
@@ -109,97 +278,206 @@ pub enum PartialCompletionRecord {
 pub struct PartialCompletionChoice {
     delta: PartialCompletionRecord,
     index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<CompletionLogprobs>,
     finish_reason: FinishReason,
 }
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
 pub struct PartialCompletionResponse {
+    /// ID to cancel the request by, shared by all choices.
+    id: String,
     object: String,
     model: String,
+    system_fingerprint: String,
     choices: Vec<PartialCompletionChoice>,
 }
 
+fn take_logprobs(
+    logprobs: &mut Vec<TokenLogprob>,
+    offset: &mut usize,
+) -> Option<CompletionLogprobs> {
+    match logprobs.is_empty() {
+        true => None,
+        false => {
+            let logprobs = CompletionLogprobs::new(std::mem::take(logprobs), *offset);
+            *offset += logprobs
+                .tokens
+                .iter()
+                .map(|token| token.chars().count())
+                .sum::<usize>();
+            Some(logprobs)
+        }
+    }
+}
+
 async fn respond_one(depot: &mut Depot, request: CompletionRequest, res: &mut Response) {
-    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
-
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
+    let Some((sender, info)) = route_model(depot, request.model.as_deref()).await else {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("model not found");
+        return;
+    };
+    let model_name = info.reload.name();
+    let fingerprint = info.reload.fingerprint();
+    let caller = Caller::new(depot);
+    if !info.has_state(request.state.as_deref(), caller.tenant.as_deref()) {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("initial state not found");
+        return;
+    }
+    if let Err(err) = request.check_prompt(&info.tokenizer) {
+        res.status_code(StatusCode::BAD_REQUEST).render(err);
+        return;
+    }
+    if reject_overload(&info, res) {
+        return;
+    }
 
-    let mut token_counter = TokenCounter::default();
-    let mut finish_reason = FinishReason::Null;
-    let mut text = String::new();
-    let mut stream = token_receiver.into_stream();
+    let logprobs = request.logprobs.is_some();
+    let id = request_id();
+    // each prompt of a batch is prefilled on its own, and shared only among its choices
+    let receivers = request
+        .choices(&caller, &id)
+        .into_iter()
+        .flat_map(|requests| request_choices(&sender, requests, info.tokenizer.clone()))
+        .collect_vec();
 
-    while let Some(token) = stream.next().await {
-        match token {
-            Token::Start => {}
-            Token::Content(token) => {
-                text += &token;
-            }
-            Token::Stop(reason, counter) => {
-                finish_reason = reason;
-                token_counter = counter;
-                break;
+    let choices = receivers
+        .into_iter()
+        .enumerate()
+        .map(|(index, token_receiver)| async move {
+            let mut token_counter = TokenCounter::default();
+            let mut finish_reason = FinishReason::Null;
+            let mut text = String::new();
+            let mut content = vec![];
+            let mut stream = token_receiver.into_stream();
+
+            while let Some(token) = stream.next().await {
+                match token {
+                    Token::Queued(_) | Token::Start => {}
+                    Token::Content(token) => {
+                        text += &token;
+                    }
+                    Token::Logprob(logprob) => content.push(logprob),
+                    Token::Stop(reason, counter) => {
+                        finish_reason = reason;
+                        token_counter = counter;
+                        break;
+                    }
+                    _ => unreachable!(),
+                }
             }
-            _ => unreachable!(),
-        }
-    }
+
+            let choice = CompletionChoice {
+                text,
+                index,
+                logprobs: logprobs.then(|| CompletionLogprobs::new(content, 0)),
+                finish_reason,
+            };
+            (choice, token_counter)
+        });
+    let (choices, counters): (Vec<_>, Vec<_>) = join_all(choices).await.into_iter().unzip();
 
     let json = Json(CompletionResponse {
+        id,
         object: "text_completion".into(),
         model: model_name,
-        choices: vec![CompletionChoice {
-            text,
-            index: 0,
-            finish_reason,
-        }],
-        counter: token_counter,
+        system_fingerprint: fingerprint,
+        choices,
+        counter: request.usage(&counters),
     });
     res.render(json);
 }
 
 async fn respond_stream(depot: &mut Depot, request: CompletionRequest, res: &mut Response) {
-    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
-
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
+    let Some((sender, info)) = route_model(depot, request.model.as_deref()).await else {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("model not found");
+        return;
+    };
+    let model_name = info.reload.name();
+    let fingerprint = info.reload.fingerprint();
+    let caller = Caller::new(depot);
+    if !info.has_state(request.state.as_deref(), caller.tenant.as_deref()) {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("initial state not found");
+        return;
+    }
+    if let Err(err) = request.check_prompt(&info.tokenizer) {
+        res.status_code(StatusCode::BAD_REQUEST).render(err);
+        return;
+    }
+    if reject_overload(&info, res) {
+        return;
+    }
+
+    let id = request_id();
+    // each prompt of a batch is prefilled on its own, and shared only among its choices
+    let receivers = request
+        .choices(&caller, &id)
+        .into_iter()
+        .flat_map(|requests| request_choices(&sender, requests, info.tokenizer.clone()))
+        .collect_vec();
 
-    let stream = token_receiver.into_stream().skip(1).map(move |token| {
+    let num_choices = receivers.len();
+    let mut logprobs: Vec<Vec<TokenLogprob>> = vec![vec![]; num_choices];
+    let mut offsets = vec![0; num_choices];
+    let mut num_done = 0;
+    let stream = select_all(
+        receivers
+            .into_iter()
+            .enumerate()
+            .map(|(index, token_receiver)| {
+                token_receiver
+                    .into_stream()
+                    .map(move |token| (index, token))
+                    .boxed()
+            }),
+    )
+    .filter_map(move |(index, token)| {
         let choice = match token {
+            Token::Queued(position) => {
+                return future::ready(Some(Ok(queued_event(index, position))))
+            }
+            Token::Start => return future::ready(None),
             Token::Content(token) => PartialCompletionChoice {
                 delta: PartialCompletionRecord::Content(token),
+                index,
+                logprobs: take_logprobs(&mut logprobs[index], &mut offsets[index]),
                 ..Default::default()
             },
+            Token::Logprob(logprob) => {
+                // log probabilities are sent along with the next chunk of content
+                logprobs[index].push(logprob);
+                return future::ready(None);
+            }
             Token::Stop(finish_reason, _) => PartialCompletionChoice {
+                index,
+                logprobs: take_logprobs(&mut logprobs[index], &mut offsets[index]),
                 finish_reason,
                 ..Default::default()
             },
-            Token::Done => return Ok(SseEvent::default().text("[DONE]")),
+            Token::Done => {
+                // the stream is done only after all choices are done
+                num_done += 1;
+                let event =
+                    (num_done == num_choices).then(|| Ok(SseEvent::default().text("[DONE]")));
+                return future::ready(event);
+            }
             _ => unreachable!(),
         };
 
-        match serde_json::to_string(&PartialCompletionResponse {
+        let event = match serde_json::to_string(&PartialCompletionResponse {
+            id: id.clone(),
             object: "text_completion.chunk".into(),
             model: model_name.clone(),
+            system_fingerprint: fingerprint.clone(),
             choices: vec![choice],
         }) {
             Ok(json_text) => Ok(SseEvent::default().text(json_text)),
             Err(err) => Err(err),
-        }
+        };
+        future::ready(Some(event))
     });
     salvo::sse::stream(res, stream);
 }
@@ -208,13 +486,65 @@ async fn respond_stream(depot: &mut Depot, request: CompletionRequest, res: &mut
 #[endpoint(
         responses(
             (status_code = 200, description = "Generate one response if `stream` is false.", body = CompletionResponse),
-            (status_code = 201, description = "Generate SSE response if `stream` is true. `StatusCode` should be 200.", body = PartialCompletionResponse)
+            (status_code = 201, description = "Generate SSE response if `stream` is true. `StatusCode` should be 200.", body = PartialCompletionResponse),
+            (status_code = 400, description = "The prompt has tokens out of the vocabulary."),
+            (status_code = 404, description = "The model or the initial state is not found."),
+            (status_code = 429, description = "The queue is full. Retry after the seconds in `Retry-After`.")
         )
     )]
 pub async fn completions(depot: &mut Depot, req: JsonBody<CompletionRequest>, res: &mut Response) {
-    let request = req.0;
+    respond(depot, req.0, res).await
+}
+
+pub(crate) async fn respond(depot: &mut Depot, request: CompletionRequest, res: &mut Response) {
     match request.stream {
         true => respond_stream(depot, request, res).await,
         false => respond_one(depot, request, res).await,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Priority;
+
+    #[test]
+    fn token_batch_usage() {
+        let request: CompletionRequest = serde_json::from_value(serde_json::json!({
+            "prompt": [[1, 2], [3, 4, 5]],
+            "n": 2,
+            "top_p": 0.5,
+            "temperature": 1.0,
+        }))
+        .unwrap();
+        let caller = Caller {
+            tenant: None,
+            priority: Priority::default(),
+            weight: 1,
+        };
+
+        let groups = request.choices(&caller, "req");
+        assert_eq!(groups.len(), 2);
+        assert!(groups.iter().all(|group| group.len() == 2));
+        assert_eq!(groups[0][1].prompt_tokens, Some(vec![1, 2]));
+        assert_eq!(groups[1][0].prompt_tokens, Some(vec![3, 4, 5]));
+
+        // what each choice reports at the end of generation
+        let counters = groups
+            .iter()
+            .flatten()
+            .map(|request| {
+                let prompt_tokens = request.prompt_tokens.as_ref().unwrap().len();
+                TokenCounter {
+                    prompt_tokens,
+                    completion_tokens: 1,
+                    total_tokens: prompt_tokens + 1,
+                }
+            })
+            .collect_vec();
+        let usage = request.usage(&counters);
+        assert_eq!(usage.prompt_tokens, 5);
+        assert_eq!(usage.completion_tokens, 4);
+        assert_eq!(usage.total_tokens, 9);
+    }
+}
@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-use futures_util::StreamExt;
+use futures_util::{future::join_all, StreamExt};
 use salvo::{
     oapi::{extract::JsonBody, ToParameters, ToResponse, ToSchema},
     prelude::*,
@@ -8,8 +8,9 @@ use salvo::{
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    api::request_info,
-    middleware::{Array, GenerateRequest, ThreadRequest, ThreadState, Token, TokenCounter},
+    api::{reject_overload, route_model, Caller},
+    middleware::{Array, GenerateRequest, Priority, ThreadRequest, Token, TokenCounter},
+    run::request_id,
 };
 
 #[derive(Debug, Default, Clone, Deserialize, ToSchema, ToParameters)]
@@ -17,18 +18,35 @@ use crate::{
 pub struct EmbeddingRequest {
     input: Array<String>,
     embed_layer: usize,
+    /// Name of the hosted model to run. Falls back to the main model.
+    model: Option<String>,
+    priority: Option<Priority>,
+    /// Seconds that the request may take. Falls back to the server default.
+    timeout: Option<f64>,
 }
 
-impl From<EmbeddingRequest> for GenerateRequest {
+impl From<EmbeddingRequest> for Vec<GenerateRequest> {
     fn from(value: EmbeddingRequest) -> Self {
-        let EmbeddingRequest { input, embed_layer } = value;
-        Self {
-            prompt: Vec::from(input).join(""),
-            max_tokens: 1,
-            embed: true,
+        let EmbeddingRequest {
+            input,
             embed_layer,
-            ..Default::default()
-        }
+            priority,
+            timeout,
+            ..
+        } = value;
+        let timeout = timeout.and_then(|timeout| Duration::try_from_secs_f64(timeout).ok());
+        Vec::from(input)
+            .into_iter()
+            .map(|prompt| GenerateRequest {
+                prompt,
+                max_tokens: 1,
+                embed: true,
+                embed_layer,
+                priority,
+                timeout,
+                ..Default::default()
+            })
+            .collect()
     }
 }
 
@@ -41,6 +59,8 @@ pub struct EmbeddingData {
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
 pub struct EmbeddingResponse {
+    /// ID to cancel the request by, shared by all inputs.
+    id: String,
     object: String,
     model: String,
     data: Vec<EmbeddingData>,
@@ -49,46 +69,72 @@ pub struct EmbeddingResponse {
 }
 
 /// Generate a embedding vector for the given text, with layer number specified for producing the embedding.
-#[endpoint]
-pub async fn embeddings(
-    depot: &mut Depot,
-    req: JsonBody<EmbeddingRequest>,
-) -> Json<EmbeddingResponse> {
+#[endpoint(
+        responses(
+            (status_code = 200, description = "Generate embeddings.", body = EmbeddingResponse),
+            (status_code = 404, description = "The model is not hosted."),
+            (status_code = 429, description = "The queue is full. Retry after the seconds in `Retry-After`.")
+        )
+    )]
+pub async fn embeddings(depot: &mut Depot, req: JsonBody<EmbeddingRequest>, res: &mut Response) {
     let request = req.to_owned(); // req.parse_json::<EmbeddingRequest>().await.unwrap();
-    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+    let Some((sender, info)) = route_model(depot, request.model.as_deref()).await else {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("model not found");
+        return;
+    };
+    if reject_overload(&info, res) {
+        return;
+    }
+    let model_name = info.reload.name();
 
-    let (token_sender, token_receiver) = flume::unbounded();
-    let _ = sender.send(ThreadRequest::Generate {
-        request: Box::new(request.into()),
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
+    // each input is queued separately so that they are processed in parallel across slots
+    let caller = Caller::new(depot);
+    let id = request_id();
+    let requests: Vec<GenerateRequest> = request.into();
+    let data = requests.into_iter().enumerate().map(|(index, request)| {
+        let request = GenerateRequest {
+            id: Some(id.clone()),
+            ..caller.apply(request)
+        };
+        let (token_sender, token_receiver) = flume::unbounded();
+        let _ = sender.send(ThreadRequest::Generate {
+            request: Box::new(request),
+            tokenizer: info.tokenizer.clone(),
+            sender: token_sender,
+        });
 
-    let mut token_counter = TokenCounter::default();
-    let mut embedding = Vec::new();
-    let mut stream = token_receiver.into_stream();
+        async move {
+            let mut token_counter = TokenCounter::default();
+            let mut embedding = Vec::new();
+            let mut stream = token_receiver.into_stream();
 
-    while let Some(token) = stream.next().await {
-        match token {
-            Token::Stop(_, counter) => token_counter = counter,
-            Token::Embed(emb) => {
-                embedding = emb;
-                break;
+            while let Some(token) = stream.next().await {
+                match token {
+                    Token::Stop(_, counter) => token_counter = counter,
+                    Token::Embed(emb) => {
+                        embedding = emb;
+                        break;
+                    }
+                    _ => {}
+                }
             }
-            _ => {}
+
+            let data = EmbeddingData {
+                object: "embedding".into(),
+                index,
+                embedding,
+            };
+            (data, token_counter)
         }
-    }
+    });
+    let (data, counters): (Vec<_>, Vec<_>) = join_all(data).await.into_iter().unzip();
 
-    Json(EmbeddingResponse {
+    res.render(Json(EmbeddingResponse {
+        id,
         object: "list".into(),
         model: model_name,
-        data: vec![EmbeddingData {
-            object: "embedding".into(),
-            index: 0,
-            embedding,
-        }],
-        counter: token_counter,
-    })
+        data,
+        counter: TokenCounter::sum(counters),
+    }));
 }
@@ -1,12 +1,11 @@
-use std::time::Duration;
-
+use itertools::Itertools;
 use salvo::{
     oapi::{ToResponse, ToSchema},
     prelude::*,
 };
 use serde::Serialize;
 
-use crate::{api::request_info, ThreadState};
+use crate::ThreadState;
 
 #[derive(Debug, Serialize, ToSchema)]
 struct ModelChoice {
@@ -19,22 +18,19 @@ pub struct ModelResponse {
     data: Vec<ModelChoice>,
 }
 
-/// Model name and id of the current choice.
+/// Names of the hosted models that are loaded.
 #[endpoint]
 pub async fn models(depot: &mut Depot) -> Json<ModelResponse> {
-    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
-    let info = request_info(sender.to_owned(), Duration::from_secs(1)).await;
-    let model_name = info
-        .reload
-        .model_path
-        .file_stem()
-        .map(|stem| stem.to_string_lossy())
-        .unwrap_or_default();
+    let ThreadState { names, .. } = depot.obtain::<ThreadState>().unwrap();
+    let names = names.read().await.keys().cloned().sorted().collect_vec();
 
     Json(ModelResponse {
-        data: vec![ModelChoice {
-            object: "models".into(),
-            id: model_name.into(),
-        }],
+        data: names
+            .into_iter()
+            .map(|id| ModelChoice {
+                object: "models".into(),
+                id,
+            })
+            .collect(),
     })
 }
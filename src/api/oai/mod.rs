@@ -1,28 +1,50 @@
 use std::sync::Arc;
 
+use flume::{Receiver, Sender};
+use itertools::Itertools;
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio::sync::RwLock;
+use web_rwkv::tokenizer::Tokenizer;
 
 pub mod chat;
 pub mod completion;
 pub mod embedding;
 pub mod info;
+pub mod tool;
 
 pub use chat::chat_completions;
 pub use completion::completions;
 pub use embedding::embeddings;
 pub use info::models;
 
-use crate::sampler::{
-    mirostat::{MirostatParams, MirostatSampler},
-    nucleus::{NucleusParams, NucleusSampler},
-    Sampler,
+use crate::{
+    middleware::{GenerateRequest, ThreadRequest, Token},
+    sampler::{
+        chain::{ChainParams, ChainSampler, Stage},
+        grammar::{Grammar, Schema},
+        min_p::{MinPParams, MinPSampler},
+        mirostat::{MirostatParams, MirostatSampler},
+        nucleus::{NucleusParams, NucleusSampler},
+        tail_free::{TailFreeParams, TailFreeSampler},
+        top_k::{TopKParams, TopKSampler},
+        typical::{TypicalParams, TypicalSampler},
+        Sampler,
+    },
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
-#[serde(untagged)]
+#[serde(untagged, try_from = "Value")]
 pub enum SamplerParams {
+    /// Variants are told apart by their fields (`samplers`, `top_k`, `min_p`, `typical_p`, `tfs_z`, `top_p` or `tau`).
+    /// `top_k` along with `top_p` runs as a chain of both. Otherwise, giving more than one of them is rejected,
+    /// unless the stages are listed in `samplers`.
+    Chain(ChainParams),
+    TopK(TopKParams),
+    MinP(MinPParams),
+    Typical(TypicalParams),
+    TailFree(TailFreeParams),
     Nucleus(NucleusParams),
     Mirostat(MirostatParams),
 }
@@ -33,11 +55,198 @@ impl Default for SamplerParams {
     }
 }
 
+impl SamplerParams {
+    /// Params for the `index`-th choice of a request.
+    /// The seed is offset so that choices of a seeded request are reproducible but still differ from each other.
+    pub fn choice(&self, index: usize) -> Self {
+        let mut params = self.clone();
+        let seed = match &mut params {
+            SamplerParams::Chain(params) => &mut params.seed,
+            SamplerParams::TopK(params) => &mut params.draw.seed,
+            SamplerParams::MinP(params) => &mut params.draw.seed,
+            SamplerParams::Typical(params) => &mut params.draw.seed,
+            SamplerParams::TailFree(params) => &mut params.draw.seed,
+            SamplerParams::Nucleus(params) => &mut params.seed,
+            SamplerParams::Mirostat(params) => &mut params.seed,
+        };
+        *seed = seed.map(|seed| seed.wrapping_add(index as u64));
+        params
+    }
+}
+
+impl TryFrom<Value> for SamplerParams {
+    type Error = serde_json::Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        use serde::de::Error;
+
+        if value.get("samplers").is_some() {
+            return serde_json::from_value(value).map(Self::Chain);
+        }
+        let keys = ["top_k", "min_p", "typical_p", "tfs_z", "top_p", "tau"]
+            .into_iter()
+            .filter(|key| value.get(key).is_some())
+            .collect_vec();
+        match keys[..] {
+            ["top_k", "top_p"] => {
+                // many clients send both, which the chain runs as top-k followed by nucleus
+                let mut value = value;
+                value["samplers"] = serde_json::to_value([
+                    Stage::Penalty,
+                    Stage::Bias,
+                    Stage::Temperature,
+                    Stage::TopK,
+                    Stage::TopP,
+                ])?;
+                serde_json::from_value(value).map(Self::Chain)
+            }
+            ["top_k"] => serde_json::from_value(value).map(Self::TopK),
+            ["min_p"] => serde_json::from_value(value).map(Self::MinP),
+            ["typical_p"] => serde_json::from_value(value).map(Self::Typical),
+            ["tfs_z"] => serde_json::from_value(value).map(Self::TailFree),
+            ["tau"] => serde_json::from_value(value).map(Self::Mirostat),
+            [] | ["top_p"] => serde_json::from_value(value).map(Self::Nucleus),
+            _ => Err(Error::custom(format!(
+                "cannot tell the sampler from `{}`; list the stages in `samplers` to combine them",
+                keys.join("`, `")
+            ))),
+        }
+    }
+}
+
 impl From<SamplerParams> for Arc<RwLock<dyn Sampler + Send + Sync>> {
     fn from(value: SamplerParams) -> Self {
         match value {
+            SamplerParams::Chain(params) => Arc::new(RwLock::new(ChainSampler::new(params))),
+            SamplerParams::TopK(params) => Arc::new(RwLock::new(TopKSampler::new(params))),
+            SamplerParams::MinP(params) => Arc::new(RwLock::new(MinPSampler::new(params))),
+            SamplerParams::Typical(params) => Arc::new(RwLock::new(TypicalSampler::new(params))),
+            SamplerParams::TailFree(params) => Arc::new(RwLock::new(TailFreeSampler::new(params))),
             SamplerParams::Nucleus(params) => Arc::new(RwLock::new(NucleusSampler::new(params))),
             SamplerParams::Mirostat(params) => Arc::new(RwLock::new(MirostatSampler::new(params))),
         }
     }
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JsonSchema {
+    #[serde(default)]
+    #[salvo(schema(value_type = Object))]
+    schema: Value,
+}
+
+/// The output of `json_object` and `json_schema` always parses,
+/// unless it is cut by `max_tokens`, in which case `finish_reason` is `length`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    JsonObject,
+    JsonSchema {
+        #[serde(default)]
+        #[salvo(schema(value_type = Object))]
+        schema: Value,
+        #[serde(default)]
+        json_schema: Option<JsonSchema>,
+    },
+}
+
+impl From<ResponseFormat> for Option<Arc<RwLock<Grammar>>> {
+    fn from(value: ResponseFormat) -> Self {
+        let schema = match value {
+            ResponseFormat::Text => return None,
+            ResponseFormat::JsonObject => Schema::object(),
+            ResponseFormat::JsonSchema {
+                json_schema: Some(JsonSchema { schema, .. }),
+                ..
+            } => Schema::new(&schema),
+            ResponseFormat::JsonSchema { schema, .. } => Schema::new(&schema),
+        };
+        Some(Arc::new(RwLock::new(Grammar::new(schema))))
+    }
+}
+
+fn default_n() -> usize {
+    1
+}
+
+/// Send the requests of all choices to the model thread, and return a token receiver for each choice.
+pub fn request_choices(
+    sender: &Sender<ThreadRequest>,
+    requests: Vec<GenerateRequest>,
+    tokenizer: Arc<Tokenizer>,
+) -> Vec<Receiver<Token>> {
+    let (mut requests, receivers): (Vec<_>, Vec<_>) = requests
+        .into_iter()
+        .map(|request| {
+            let (token_sender, token_receiver) = flume::unbounded();
+            ((request, token_sender), token_receiver)
+        })
+        .unzip();
+
+    let _ = match requests.len() {
+        1 => {
+            let (request, token_sender) = requests.remove(0);
+            sender.send(ThreadRequest::Generate {
+                request: Box::new(request),
+                tokenizer,
+                sender: token_sender,
+            })
+        }
+        _ => sender.send(ThreadRequest::Fork {
+            requests,
+            tokenizer,
+        }),
+    };
+    receivers
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(value: Value) -> Result<SamplerParams, serde_json::Error> {
+        SamplerParams::try_from(value)
+    }
+
+    #[test]
+    fn sampler_params() {
+        let params = parse(json!({"top_p": 0.9, "temperature": 1.0})).unwrap();
+        assert!(matches!(params, SamplerParams::Nucleus(x) if x.top_p == 0.9));
+        let params = parse(json!({"top_k": 20})).unwrap();
+        assert!(matches!(params, SamplerParams::TopK(x) if x.top_k == 20));
+        let params = parse(json!({"min_p": 0.1})).unwrap();
+        assert!(matches!(params, SamplerParams::MinP(_)));
+        let params = parse(json!({"typical_p": 0.9})).unwrap();
+        assert!(matches!(params, SamplerParams::Typical(_)));
+        let params = parse(json!({"tfs_z": 0.9})).unwrap();
+        assert!(matches!(params, SamplerParams::TailFree(_)));
+        let params =
+            parse(json!({"samplers": ["top_k", "min_p"], "top_k": 20, "min_p": 0.1})).unwrap();
+        assert!(
+            matches!(params, SamplerParams::Chain(x) if x.samplers == [Stage::TopK, Stage::MinP])
+        );
+    }
+
+    #[test]
+    fn top_k_with_top_p() {
+        let params = parse(json!({"top_p": 0.9, "top_k": 40, "temperature": 0.7})).unwrap();
+        let SamplerParams::Chain(params) = params else {
+            panic!("expected a chain, got {params:?}");
+        };
+        assert_eq!(params.top_k, 40);
+        assert_eq!(params.top_p, 0.9);
+        assert_eq!(params.temperature, 0.7);
+        assert_eq!(params.samplers[3..], [Stage::TopK, Stage::TopP]);
+    }
+
+    #[test]
+    fn ambiguous() {
+        assert!(parse(json!({"top_k": 40, "min_p": 0.1})).is_err());
+        assert!(parse(json!({"top_p": 0.9, "tau": 0.5})).is_err());
+        assert!(parse(json!({"top_p": 0.9, "top_k": 40, "tfs_z": 0.9})).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+use itertools::Itertools;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const TOOL_CALL_BEGIN: &str = "<tool_call>";
+const TOOL_CALL_END: &str = "</tool_call>";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Function {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    #[salvo(schema(value_type = Object))]
+    parameters: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Tool {
+    #[serde(rename = "type", default = "default_tool_type")]
+    ty: String,
+    function: Function,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FunctionCall {
+    name: String,
+    /// Arguments of the call, in JSON.
+    arguments: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ToolCall {
+    id: String,
+    #[serde(rename = "type", default = "default_tool_type")]
+    ty: String,
+    function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PartialToolCall {
+    index: usize,
+    #[serde(flatten)]
+    call: ToolCall,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolChoiceMode {
+    /// The model never calls any tool.
+    None,
+    /// The model decides whether to call a tool or not.
+    #[default]
+    Auto,
+    /// The model must call one or more tools.
+    Required,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FunctionName {
+    name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct NamedToolChoice {
+    #[serde(rename = "type", default = "default_tool_type")]
+    ty: String,
+    function: FunctionName,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function(NamedToolChoice),
+}
+
+impl Default for ToolChoice {
+    fn default() -> Self {
+        Self::Mode(ToolChoiceMode::Auto)
+    }
+}
+
+impl ToolChoice {
+    pub fn is_none(&self) -> bool {
+        matches!(self, Self::Mode(ToolChoiceMode::None))
+    }
+
+    /// Text that the reply of the assistant is forced to begin with.
+    pub fn prefix(&self) -> String {
+        match self {
+            Self::Mode(ToolChoiceMode::Required) => TOOL_CALL_BEGIN.into(),
+            Self::Function(NamedToolChoice { function, .. }) => {
+                let name = serde_json::to_string(&function.name).unwrap_or_default();
+                format!("{TOOL_CALL_BEGIN}{{\"name\": {name}, \"arguments\": ")
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+fn default_tool_type() -> String {
+    "function".into()
+}
+
+/// Render the tool list as instructions for the model.
+pub fn render_tools(tools: &[Tool]) -> String {
+    let tools = tools
+        .iter()
+        .map(|tool| serde_json::to_string(&tool.function).unwrap_or_default())
+        .join("\n");
+    format!(
+        "You can call the following tools. To call a tool, reply with \
+        {TOOL_CALL_BEGIN}{{\"name\": <tool name>, \"arguments\": <arguments in JSON>}}{TOOL_CALL_END}, \
+        and then wait for the result of the tool, which is given as \
+        {{\"tool_call_id\": <id of the call>, \"content\": <result>}}.\n{tools}"
+    )
+}
+
+/// Render tool calls in the same format that the model is asked to reply with.
+/// The ID is kept so that the results of parallel calls can be told apart.
+pub fn render_tool_calls(calls: &[ToolCall]) -> String {
+    calls
+        .iter()
+        .map(|call| {
+            let FunctionCall { name, arguments } = &call.function;
+            let arguments = serde_json::from_str::<Value>(arguments)
+                .unwrap_or_else(|_| Value::String(arguments.clone()));
+            let call = serde_json::json!({ "id": call.id, "name": name, "arguments": arguments });
+            format!("{TOOL_CALL_BEGIN}{call}{TOOL_CALL_END}")
+        })
+        .join("\n")
+}
+
+/// Render the result of a tool, along with the ID of the call that it answers.
+pub fn render_tool_result(id: &str, content: &str) -> String {
+    serde_json::json!({ "tool_call_id": id, "content": content }).to_string()
+}
+
+fn parse_tool_call(body: &str) -> Option<ToolCall> {
+    #[derive(Deserialize)]
+    struct Call {
+        name: String,
+        #[serde(default)]
+        arguments: Value,
+    }
+
+    let Call { name, arguments } = serde_json::from_str(body.trim()).ok()?;
+    let arguments = match arguments {
+        Value::String(arguments) => arguments,
+        arguments => arguments.to_string(),
+    };
+    Some(ToolCall {
+        id: format!("call_{:016x}", fastrand::u64(..)),
+        ty: default_tool_type(),
+        function: FunctionCall { name, arguments },
+    })
+}
+
+/// Separates tool calls from the content generated by the model.
+#[derive(Debug, Default, Clone)]
+pub struct ToolCallParser {
+    buffer: String,
+    num_calls: usize,
+}
+
+impl ToolCallParser {
+    /// Create a parser, with `prefix` being the text the reply is forced to begin with.
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            buffer: prefix.into(),
+            num_calls: 0,
+        }
+    }
+
+    /// Number of tool calls that are parsed so far.
+    pub fn num_calls(&self) -> usize {
+        self.num_calls
+    }
+
+    /// Feed generated text. Returns the text that is safe to be sent as content, and the tool calls completed.
+    pub fn feed(&mut self, text: &str) -> (String, Vec<ToolCall>) {
+        self.buffer.push_str(text);
+
+        let mut content = String::new();
+        let mut calls = vec![];
+        loop {
+            let Some(begin) = self.buffer.find(TOOL_CALL_BEGIN) else {
+                // hold back the tail which may be the beginning of a tool call
+                let len = (1..TOOL_CALL_BEGIN.len())
+                    .rev()
+                    .find(|&len| self.buffer.ends_with(&TOOL_CALL_BEGIN[..len]))
+                    .unwrap_or_default();
+                let mid = self.buffer.len() - len;
+                content.push_str(&self.buffer[..mid]);
+                self.buffer = self.buffer[mid..].to_string();
+                break;
+            };
+
+            content.push_str(&self.buffer[..begin]);
+            let body = &self.buffer[begin + TOOL_CALL_BEGIN.len()..];
+            let Some(end) = body.find(TOOL_CALL_END) else {
+                self.buffer = self.buffer[begin..].to_string();
+                break;
+            };
+            match parse_tool_call(&body[..end]) {
+                Some(call) => calls.push(call),
+                None => content.push_str(
+                    &self.buffer[begin..][..TOOL_CALL_BEGIN.len() + end + TOOL_CALL_END.len()],
+                ),
+            }
+            self.buffer = body[end + TOOL_CALL_END.len()..].to_string();
+        }
+
+        self.num_calls += calls.len();
+        (content, calls)
+    }
+
+    /// Flush the text held back at the end of generation.
+    /// A tool call that is cut off by stop words is still accepted if its body is valid.
+    pub fn finish(&mut self) -> (String, Vec<ToolCall>) {
+        let buffer = std::mem::take(&mut self.buffer);
+        match buffer
+            .strip_prefix(TOOL_CALL_BEGIN)
+            .and_then(parse_tool_call)
+        {
+            Some(call) => {
+                self.num_calls += 1;
+                (String::new(), vec![call])
+            }
+            None => (buffer, vec![]),
+        }
+    }
+
+    /// Tag the tool calls with their indices, given that they are the last ones parsed.
+    pub fn index(&self, calls: Vec<ToolCall>) -> Vec<PartialToolCall> {
+        let start = self.num_calls - calls.len();
+        calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| PartialToolCall {
+                index: start + index,
+                call,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `chunks` one by one, then finish, and return the content and the calls as (name, arguments).
+    fn parse(prefix: &str, chunks: &[&str]) -> (String, Vec<(String, String)>) {
+        let mut parser = ToolCallParser::new(prefix);
+        let mut content = String::new();
+        let mut calls = vec![];
+        for chunk in chunks {
+            let (text, new_calls) = parser.feed(chunk);
+            content.push_str(&text);
+            calls.extend(new_calls);
+        }
+        let (text, new_calls) = parser.finish();
+        content.push_str(&text);
+        calls.extend(new_calls);
+        assert_eq!(parser.num_calls(), calls.len());
+
+        let calls = calls
+            .into_iter()
+            .map(|call| (call.function.name, call.function.arguments))
+            .collect();
+        (content, calls)
+    }
+
+    #[test]
+    fn split_feed() {
+        let text = r#"Sure. <tool_call>{"name": "get_weather", "arguments": {"city": "Paris"}}</tool_call>"#;
+        let expected = (
+            "Sure. ".to_string(),
+            vec![("get_weather".to_string(), r#"{"city":"Paris"}"#.to_string())],
+        );
+
+        // every split point, including those inside the tags, `"name"` and the arguments
+        for mid in (1..text.len()).filter(|&mid| text.is_char_boundary(mid)) {
+            let (head, tail) = text.split_at(mid);
+            assert_eq!(parse("", &[head, tail]), expected, "split at {mid}");
+        }
+        let chunks = text.as_bytes().chunks(3).collect_vec();
+        let chunks = chunks
+            .iter()
+            .map(|x| std::str::from_utf8(x).unwrap())
+            .collect_vec();
+        assert_eq!(parse("", &chunks), expected);
+    }
+
+    #[test]
+    fn content_is_not_held_back() {
+        let mut parser = ToolCallParser::new("");
+        let (text, calls) = parser.feed("Hello <tool");
+        assert_eq!(text, "Hello ");
+        assert!(calls.is_empty());
+        let (text, calls) = parser.feed(" world");
+        assert_eq!(text, "<tool world");
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn finish_tail() {
+        // a call cut off before its end tag is still accepted
+        let (content, calls) = parse("", &[r#"<tool_call>{"name": "f", "arguments": {}}"#]);
+        assert_eq!(content, "");
+        assert_eq!(calls, vec![("f".to_string(), "{}".to_string())]);
+
+        // a held back tail that is not a call is flushed as content
+        assert_eq!(parse("", &["a <tool_c"]), ("a <tool_c".to_string(), vec![]));
+        let (content, calls) = parse("", &[r#"<tool_call>{"name": "#]);
+        assert_eq!(content, r#"<tool_call>{"name": "#);
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn named_prefix() {
+        let choice: ToolChoice = serde_json::from_value(
+            serde_json::json!({"type": "function", "function": {"name": "f"}}),
+        )
+        .unwrap();
+        let prefix = choice.prefix();
+        let (content, calls) = parse(&prefix, &[r#"{"x": "#, "1}}", "</tool_call>"]);
+        assert_eq!(content, "");
+        assert_eq!(calls, vec![("f".to_string(), r#"{"x":1}"#.to_string())]);
+
+        let choice = ToolChoice::Mode(ToolChoiceMode::Required);
+        let (_, calls) = parse(&choice.prefix(), &[r#"{"name": "g"}</tool_call>"#]);
+        assert_eq!(calls, vec![("g".to_string(), "null".to_string())]);
+    }
+
+    #[test]
+    fn lookalike_content() {
+        // text that looks like the start of a tool call, but is not
+        let text = "Use <tool> or <tool_cal> tags, and a < b.";
+        assert_eq!(parse("", &[text]), (text.to_string(), vec![]));
+
+        // a complete call with an invalid body is kept as content
+        let text = "<tool_call>not json</tool_call> done";
+        assert_eq!(parse("", &[text]), (text.to_string(), vec![]));
+    }
+}
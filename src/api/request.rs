@@ -0,0 +1,44 @@
+use salvo::prelude::*;
+use serde::Serialize;
+
+use super::Caller;
+use crate::middleware::{ThreadRequest, ThreadState};
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CancelResponse {
+    /// ID of the cancelled request.
+    id: String,
+}
+
+/// `/api/requests/<id>/cancel`.
+/// Removes the request from the queue, or stops it if it is running.
+/// Generation ends with the finish reason `cancelled`. Callers can only cancel their own requests.
+#[handler]
+pub async fn cancel(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let ThreadState { sender, models, .. } = depot.obtain::<ThreadState>().unwrap();
+    let id = req.param::<String>("id").unwrap_or_default();
+    let tenant = Caller::new(depot).tenant;
+
+    // the id does not tell which model runs the request, so ask all of them
+    let mut replies = vec![];
+    for sender in std::iter::once(sender).chain(models.iter()) {
+        let (cancel_sender, cancel_receiver) = flume::unbounded();
+        let _ = sender.send(ThreadRequest::Cancel {
+            id: id.clone(),
+            tenant: tenant.clone(),
+            sender: cancel_sender,
+        });
+        replies.push(cancel_receiver.recv_async().await);
+    }
+    match replies.iter().any(|reply| matches!(reply, Ok(true))) {
+        true => res.render(Json(CancelResponse { id })),
+        false => match replies.iter().any(|reply| reply.is_ok()) {
+            true => res
+                .status_code(StatusCode::NOT_FOUND)
+                .render("request not found"),
+            false => res
+                .status_code(StatusCode::SERVICE_UNAVAILABLE)
+                .render("model is not loaded"),
+        },
+    };
+}
@@ -0,0 +1,107 @@
+use salvo::prelude::*;
+
+use super::{
+    oai::completion::{self, CompletionRequest},
+    route_model, Caller,
+};
+use crate::middleware::{SessionError, SessionInfo, SessionRequest, ThreadRequest, ThreadState};
+
+async fn request_session(
+    depot: &mut Depot,
+    request: SessionRequest,
+) -> Result<SessionInfo, SessionError> {
+    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+    let (info_sender, info_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Session {
+        request,
+        tenant: Caller::new(depot).tenant,
+        sender: info_sender,
+    });
+    info_receiver
+        .recv_async()
+        .await
+        .unwrap_or(Err(SessionError::NotFound))
+}
+
+fn render_error(err: SessionError, res: &mut Response) {
+    match err {
+        SessionError::NotFound => res
+            .status_code(StatusCode::NOT_FOUND)
+            .render("session not found"),
+        SessionError::Full => res
+            .status_code(StatusCode::TOO_MANY_REQUESTS)
+            .render("too many sessions"),
+    };
+}
+
+async fn render_session(depot: &mut Depot, request: SessionRequest, res: &mut Response) {
+    match request_session(depot, request).await {
+        Ok(session) => res.render(Json(session)),
+        Err(err) => render_error(err, res),
+    };
+}
+
+/// `/api/sessions`.
+#[handler]
+pub async fn create(depot: &mut Depot, res: &mut Response) {
+    render_session(depot, SessionRequest::Create, res).await
+}
+
+/// `/api/sessions/<id>`.
+#[handler]
+pub async fn info(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    render_session(depot, SessionRequest::Info(id), res).await
+}
+
+/// `/api/sessions/<id>/fork`.
+#[handler]
+pub async fn fork(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    render_session(depot, SessionRequest::Fork(id), res).await
+}
+
+/// `/api/sessions/<id>`.
+#[handler]
+pub async fn delete(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    render_session(depot, SessionRequest::Delete(id), res).await
+}
+
+/// `/api/sessions/<id>/completions`.
+/// Takes the same body as `/oai/completions`, but the prompt only holds the new turn of the conversation.
+#[handler]
+pub async fn completions(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    if let Err(err) = request_session(depot, SessionRequest::Info(id.clone())).await {
+        render_error(err, res);
+        return;
+    }
+
+    let request = match req.parse_json::<CompletionRequest>().await {
+        Ok(mut request) => {
+            request.session = Some(id);
+            request
+        }
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST)
+                .render(err.to_string());
+            return;
+        }
+    };
+
+    // sessions live in the main model, so the request cannot pick another one
+    if request.model.is_some() {
+        let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+        let sender = sender.clone();
+        match route_model(depot, request.model.as_deref()).await {
+            Some((other, _)) if other.same_channel(&sender) => {}
+            _ => {
+                res.status_code(StatusCode::BAD_REQUEST)
+                    .render("sessions only run on the main model");
+                return;
+            }
+        }
+    }
+    completion::respond(depot, request, res).await
+}
@@ -0,0 +1,123 @@
+use salvo::{
+    http::header::{HeaderValue, CONTENT_TYPE},
+    prelude::*,
+};
+use serde::Deserialize;
+
+use super::{reject_overload, route_model, Caller};
+use crate::middleware::{GenerateRequest, ThreadRequest, Token};
+
+/// States of large models take hundreds of megabytes.
+const MAX_STATE_SIZE: usize = 1 << 30;
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExportRequest {
+    /// The prompt to run.
+    prompt: String,
+    /// Name of the initial state to start from.
+    state: Option<String>,
+    /// Name of the hosted model to run. Falls back to the main model.
+    model: Option<String>,
+}
+
+/// `/api/state/export`.
+/// Runs the prompt and responds the resulting state in safetensors.
+#[handler]
+pub async fn export(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let ExportRequest {
+        prompt,
+        state,
+        model,
+    } = match req.parse_json().await {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST)
+                .render(err.to_string());
+            return;
+        }
+    };
+    if prompt.is_empty() {
+        res.status_code(StatusCode::BAD_REQUEST)
+            .render("prompt is empty");
+        return;
+    }
+    let Some((sender, info)) = route_model(depot, model.as_deref()).await else {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("model not found");
+        return;
+    };
+    let caller = Caller::new(depot);
+    if !info.has_state(state.as_deref(), caller.tenant.as_deref()) {
+        res.status_code(StatusCode::NOT_FOUND)
+            .render("initial state not found");
+        return;
+    }
+    if reject_overload(&info, res) {
+        return;
+    }
+
+    // the state is backed once the prompt is processed and the first token is sampled
+    let request = caller.apply(GenerateRequest {
+        prompt,
+        max_tokens: 0,
+        state,
+        export: true,
+        ..Default::default()
+    });
+    let (token_sender, token_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Generate {
+        request: Box::new(request),
+        tokenizer: info.tokenizer,
+        sender: token_sender,
+    });
+
+    while let Ok(token) = token_receiver.recv_async().await {
+        if let Token::State(data) = token {
+            res.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            let _ = res.write_body(data);
+            return;
+        }
+    }
+    res.status_code(StatusCode::INTERNAL_SERVER_ERROR)
+        .render("failed to export state");
+}
+
+/// `/api/state/import?name=<name>&model=<model>`.
+/// Takes a state in safetensors as the body, which requests to the model can then start from by `state`.
+/// States configured at reload cannot be replaced, so that a caller never changes what others start from.
+/// Under tenant isolation, the imported state is only visible to the caller.
+#[handler]
+pub async fn import(depot: &mut Depot, req: &mut Request) -> StatusCode {
+    let name = match req.query::<String>("name") {
+        Some(name) if !name.is_empty() => name,
+        _ => return StatusCode::BAD_REQUEST,
+    };
+    let model = req.query::<String>("model");
+    let Some((sender, info)) = route_model(depot, model.as_deref()).await else {
+        return StatusCode::NOT_FOUND;
+    };
+    if info.reload.state.iter().any(|init| init.name == name) {
+        return StatusCode::CONFLICT;
+    }
+    let data = match req.payload_with_max_size(MAX_STATE_SIZE).await {
+        Ok(data) => data.to_vec(),
+        Err(_) => return StatusCode::BAD_REQUEST,
+    };
+
+    let (result_sender, result_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::ImportState {
+        name,
+        data,
+        tenant: Caller::new(depot).tenant,
+        sender: result_sender,
+    });
+    match result_receiver.recv_async().await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::UNPROCESSABLE_ENTITY,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use qp_trie::Trie;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::run::{AsTokenSlice, Tokens};
+
+const TOKENS_EXTENSION: &str = "tokens";
+const STATE_EXTENSION: &str = "state";
+
+/// A state file indexed by the cache.
+#[derive(Debug, Clone)]
+pub struct DiskItem {
+    name: String,
+    size: u64,
+    instant: SystemTime,
+}
+
+/// Backed states serialized on disk, keyed by the token prefixes that produce them.
+/// This is the second tier of the state cache, which survives restarts.
+#[derive(Debug)]
+pub struct DiskCache {
+    path: PathBuf,
+    budget: u64,
+    size: u64,
+    items: Trie<Tokens, DiskItem>,
+}
+
+fn encode_tokens(tokens: &[u16]) -> Vec<u8> {
+    tokens
+        .iter()
+        .flat_map(|token| token.to_le_bytes())
+        .collect()
+}
+
+fn decode_tokens(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .collect()
+}
+
+fn file_name(tokens: &[u16]) -> String {
+    let mut sha = Sha256::new();
+    sha.update(encode_tokens(tokens));
+    format!("{:x}", sha.finalize())
+}
+
+impl DiskCache {
+    /// Open the cache directory, and index states stored by earlier runs.
+    /// `budget` is the maximum total size of states in bytes.
+    pub fn new(path: impl AsRef<Path>, budget: u64) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        let mut cache = Self {
+            path,
+            budget,
+            size: 0,
+            items: Trie::new(),
+        };
+        for entry in fs::read_dir(&cache.path)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != TOKENS_EXTENSION) {
+                continue;
+            }
+            let Some(name) = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+            else {
+                continue;
+            };
+
+            let state_path = path.with_extension(STATE_EXTENSION);
+            let Ok(meta) = fs::metadata(&state_path) else {
+                // the state is gone, so is the entry
+                let _ = fs::remove_file(&path);
+                continue;
+            };
+            let tokens = decode_tokens(&fs::read(&path)?);
+            let item = DiskItem {
+                name,
+                size: meta.len(),
+                instant: meta.modified().unwrap_or(SystemTime::now()),
+            };
+            cache.size += item.size;
+            cache.items.insert(Tokens(tokens), item);
+        }
+        log::info!(
+            "indexed {} states of {} bytes on disk",
+            cache.items.count(),
+            cache.size
+        );
+
+        let files = cache.limit();
+        Self::delete(files);
+        Ok(cache)
+    }
+
+    /// Search for the longest prefix of `tokens` that has a state on disk.
+    pub fn longest_prefix(&self, tokens: &[u16]) -> Vec<u16> {
+        let prefix = self.items.longest_common_prefix(tokens.as_token_slice());
+        let len = (1..=prefix.len())
+            .rev()
+            .find(|len| self.items.contains_key(prefix[0..*len].as_token_slice()))
+            .unwrap_or_default();
+        prefix[0..len].to_vec()
+    }
+
+    /// Mark the state of exactly `tokens` as recently used, and return the path of its file.
+    pub fn touch(&mut self, tokens: &[u16]) -> Option<PathBuf> {
+        let item = self.items.get_mut(tokens.as_token_slice())?;
+        item.instant = SystemTime::now();
+        Some(self.path.join(&item.name).with_extension(STATE_EXTENSION))
+    }
+
+    /// Directory of the cache, where [`DiskCache::write`] puts new states.
+    pub fn dir(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read a state file returned by [`DiskCache::touch`].
+    /// This blocks on file IO, so call it on a blocking thread without holding the cache.
+    pub fn read<B: DeserializeOwned>(path: &Path) -> Result<B> {
+        let data = fs::read(path)?;
+        match cbor4ii::serde::from_slice(&data) {
+            Ok(backed) => Ok(backed),
+            Err(err) => bail!("failed to decode state: {err}"),
+        }
+    }
+
+    /// Write the state of `tokens` into `dir`. The returned item is then indexed by [`DiskCache::insert`].
+    /// This blocks on file IO, so call it on a blocking thread without holding the cache.
+    pub fn write<B: Serialize>(dir: &Path, tokens: &[u16], backed: &B) -> Result<DiskItem> {
+        let data = match cbor4ii::serde::to_vec(Vec::new(), backed) {
+            Ok(data) => data,
+            Err(err) => bail!("failed to encode state: {err}"),
+        };
+
+        let name = file_name(tokens);
+        let path = dir.join(&name);
+        fs::write(path.with_extension(TOKENS_EXTENSION), encode_tokens(tokens))?;
+        fs::write(path.with_extension(STATE_EXTENSION), &data)?;
+
+        Ok(DiskItem {
+            name,
+            size: data.len() as u64,
+            instant: SystemTime::now(),
+        })
+    }
+
+    /// Index a state written by [`DiskCache::write`], and evict old states if the cache runs out of its budget.
+    /// Returns the files of evicted states, which are to be passed to [`DiskCache::delete`].
+    pub fn insert(&mut self, tokens: Vec<u16>, item: DiskItem) -> Vec<PathBuf> {
+        self.size += item.size;
+        if let Some(old) = self.items.insert(Tokens(tokens), item) {
+            self.size -= old.size;
+        }
+        self.limit()
+    }
+
+    /// Remove the state of `tokens` from the index, and return its files.
+    pub fn remove(&mut self, tokens: &[u16]) -> Option<PathBuf> {
+        let item = self.items.remove(tokens.as_token_slice())?;
+        self.size -= item.size;
+        Some(self.path.join(&item.name))
+    }
+
+    /// Remove all states that start with `prefix` from the index, and return their files.
+    pub fn flush(&mut self, prefix: &[u16]) -> Vec<PathBuf> {
+        let removing = self
+            .items
+            .iter_prefix(prefix.as_token_slice())
+            .map(|(tokens, _)| tokens.0.clone())
+            .collect_vec();
+        removing
+            .iter()
+            .filter_map(|tokens| self.remove(tokens))
+            .collect()
+    }
+
+    /// Delete the files of removed states.
+    /// This blocks on file IO, so call it on a blocking thread without holding the cache.
+    pub fn delete(files: impl IntoIterator<Item = PathBuf>) {
+        for path in files {
+            let _ = fs::remove_file(path.with_extension(TOKENS_EXTENSION));
+            let _ = fs::remove_file(path.with_extension(STATE_EXTENSION));
+        }
+    }
+
+    /// Remove the least recently used states until the cache fits in the budget, and return their files.
+    fn limit(&mut self) -> Vec<PathBuf> {
+        if self.size <= self.budget {
+            return vec![];
+        }
+
+        let items = self
+            .items
+            .iter()
+            .sorted_unstable_by_key(|(_, item)| item.instant)
+            .map(|(tokens, _)| tokens.0.clone())
+            .collect_vec();
+        let mut files = vec![];
+        for tokens in items {
+            if self.size <= self.budget {
+                break;
+            }
+            files.extend(self.remove(&tokens));
+        }
+        files
+    }
+}
@@ -1,22 +1,44 @@
 use std::{
     net::{IpAddr, Ipv4Addr},
     path::PathBuf,
+    time::Duration,
 };
 
 use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 use web_rwkv::model::{EmbedDevice, Quant};
 
-use crate::{build_path, middleware::ReloadRequest};
+use crate::{
+    build_path,
+    middleware::{Priority, ReloadRequest},
+};
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Config {
     pub model: Model,
     pub lora: Vec<Lora>,
+    pub state: Vec<InitState>,
     pub tokenizer: Tokenizer,
+    pub cache: Cache,
+    pub scheduler: Scheduler,
     pub adapter: AdapterOption,
     pub listen: ListenerOption,
+    /// Other models that are hosted along with the main one. Requests select them by `model`.
+    pub models: Vec<HostedModel>,
+}
+
+/// Everything to load a model with. Each hosted model has its own runtime and queue.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HostedModel {
+    pub model: Model,
+    pub lora: Vec<Lora>,
+    pub state: Vec<InitState>,
+    pub tokenizer: Tokenizer,
+    pub cache: Cache,
+    pub scheduler: Scheduler,
+    pub adapter: AdapterOption,
 }
 
 impl TryFrom<Config> for ReloadRequest {
@@ -24,8 +46,36 @@ impl TryFrom<Config> for ReloadRequest {
 
     fn try_from(value: Config) -> Result<Self, Self::Error> {
         let Config {
+            model,
+            lora,
+            state,
+            tokenizer,
+            cache,
+            scheduler,
+            adapter,
+            ..
+        } = value;
+        HostedModel {
+            model,
+            lora,
+            state,
+            tokenizer,
+            cache,
+            scheduler,
+            adapter,
+        }
+        .try_into()
+    }
+}
+
+impl TryFrom<HostedModel> for ReloadRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: HostedModel) -> Result<Self, Self::Error> {
+        let HostedModel {
             model:
                 Model {
+                    name,
                     model_name,
                     model_path,
                     quant,
@@ -38,21 +88,30 @@ impl TryFrom<Config> for ReloadRequest {
                     embed_device,
                 },
             mut lora,
-            tokenizer: Tokenizer {
-                path: tokenizer_path,
-            },
+            mut state,
+            tokenizer:
+                Tokenizer {
+                    path: tokenizer_path,
+                    penalty_free_list,
+                },
+            cache,
+            scheduler,
             adapter,
-            ..
         } = value;
 
         for lora in lora.iter_mut() {
             lora.path = build_path(&model_path, &lora.path)?;
         }
+        for state in state.iter_mut() {
+            state.path = build_path(&model_path, &state.path)?;
+        }
         let model_path = build_path(&model_path, model_name)?;
 
         Ok(Self {
+            name,
             model_path,
             lora,
+            state,
             quant,
             quant_type,
             turbo,
@@ -62,6 +121,9 @@ impl TryFrom<Config> for ReloadRequest {
             max_batch,
             embed_device,
             tokenizer_path,
+            penalty_free_list,
+            cache,
+            scheduler,
             adapter,
         })
     }
@@ -76,6 +138,8 @@ pub struct Model {
     pub model_path: PathBuf,
     /// Name of the model.
     pub model_name: PathBuf,
+    /// Name that requests select the model by. Defaults to the file name of the model without extension.
+    pub name: Option<String>,
     /// Specify layers that needs to be quantized.
     pub quant: usize,
     /// Quantization type (Int8 or NF4).
@@ -113,8 +177,98 @@ pub struct Lora {
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(default)]
+pub struct InitState {
+    /// Name that requests use to select the state.
+    pub name: String,
+    /// Path to the state, which is a safetensors file of a backed state.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
 pub struct Tokenizer {
     pub path: PathBuf,
+    /// Tokens containing any of these are exempted from presence and frequency penalties.
+    #[derivative(Default(value = "default_penalty_free_list()"))]
+    pub penalty_free_list: Vec<String>,
+}
+
+pub fn default_penalty_free_list() -> Vec<String> {
+    ["\n", ",", ".", "\u{002f}"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct Cache {
+    /// Directory to store backed states that are evicted from memory. The disk cache is disabled if not set.
+    pub disk_path: Option<PathBuf>,
+    /// Maximum size of states stored on disk, in MiB.
+    #[derivative(Default(value = "4096"))]
+    pub disk_budget: usize,
+    /// Maximum size of states cached in memory, in MiB.
+    #[derivative(Default(value = "4096"))]
+    pub memory_budget: usize,
+    /// Which states to evict first when the cache runs out of its memory budget.
+    pub policy: CachePolicy,
+    /// Prompts longer than this are cached before generation, so that regenerations can skip the prompt.
+    #[derivative(Default(value = "32"))]
+    pub prompt_cache_tokens: usize,
+    /// Partition cached states by the `sid` of the caller's token, so that tenants never share states.
+    /// Warm-up prompts and the disk cache only serve requests without a token then.
+    pub tenant_isolation: bool,
+    /// Prompts that are prefilled after the model loads. Their states are never evicted.
+    pub warmup: Vec<Warmup>,
+    /// Maximum number of sessions. Creating more sessions fails until some are deleted or expired.
+    #[derivative(Default(value = "256"))]
+    pub max_sessions: usize,
+    /// Seconds that a session may stay idle before it is dropped.
+    #[derivative(Default(value = "3600"))]
+    pub session_ttl: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Warmup {
+    /// The prompt to prefill, e.g., a long system prompt shared by many requests.
+    pub prompt: String,
+}
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct Scheduler {
+    /// Maximum number of requests waiting for a free slot. Requests beyond this are rejected with `429`.
+    #[derivative(Default(value = "64"))]
+    pub max_queue_len: usize,
+    /// Seconds that rejected clients are told to wait before retrying.
+    #[derivative(Default(value = "5"))]
+    pub retry_after: u64,
+    /// Seconds that a request may take from its arrival to its end, for requests that do not set their own.
+    /// Requests are not limited if not set.
+    pub timeout: Option<f64>,
+}
+
+impl Scheduler {
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+            .and_then(|timeout| Duration::try_from_secs_f64(timeout).ok())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CachePolicy {
+    /// Evict the least recently used states.
+    #[default]
+    Lru,
+    /// Evict the least frequently used states.
+    Lfu,
+    /// Evict states that no other cached state extends, so that shared ancestors are kept.
+    Prefix,
 }
 
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
@@ -130,6 +284,17 @@ pub enum AdapterOption {
 pub struct AppKey {
     pub app_id: String,
     pub secret_key: String,
+    /// Priority of requests with this key that do not set their own.
+    #[serde(default)]
+    pub priority: Priority,
+    /// Share of the slots that this key gets relative to other keys when the server is busy.
+    #[derivative(Default(value = "1"))]
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+}
+
+fn default_weight() -> usize {
+    AppKey::default().weight
 }
 
 #[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
@@ -22,13 +22,16 @@ use salvo::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::middleware::{model_route, ThreadState};
+use crate::middleware::{model_route, ModelNames, ThreadState};
 
 mod api;
+mod cache;
 mod config;
 mod middleware;
+mod queue;
 mod run;
 mod sampler;
+mod state;
 
 pub fn build_path(path: impl AsRef<Path>, name: impl AsRef<Path>) -> Result<PathBuf> {
     let permitted = path.as_ref();
@@ -113,8 +116,12 @@ async fn main() {
         .unwrap();
 
     let args = Args::parse();
+    let names = ModelNames::default();
     let (sender, receiver) = flume::unbounded::<ThreadRequest>();
-    tokio::task::spawn_blocking(move || model_route(receiver));
+    {
+        let (sender, names) = (sender.clone(), names.clone());
+        tokio::task::spawn_blocking(move || model_route(receiver, sender, names));
+    }
 
     let (listen, config) = {
         let path = args
@@ -133,6 +140,26 @@ async fn main() {
         sender: None,
     });
 
+    let models = config
+        .models
+        .iter()
+        .cloned()
+        .map(|model| {
+            let (sender, receiver) = flume::unbounded::<ThreadRequest>();
+            {
+                let (sender, names) = (sender.clone(), names.clone());
+                tokio::task::spawn_blocking(move || model_route(receiver, sender, names));
+            }
+
+            let request = Box::new(model.try_into().unwrap());
+            let _ = sender.send(ThreadRequest::Reload {
+                request,
+                sender: None,
+            });
+            sender
+        })
+        .collect();
+
     let serve_path = {
         let path = tempfile::tempdir()
             .expect("create temp dir failed")
@@ -193,6 +220,20 @@ async fn main() {
         .push(Router::with_path("/models/unload").get(api::unload))
         .push(Router::with_path("/models/state").get(api::state))
         .push(Router::with_path("/models/list").get(api::models))
+        .push(Router::with_path("/cache/list").get(api::cache::list))
+        .push(Router::with_path("/cache/stats").get(api::cache::stats))
+        .push(Router::with_path("/cache/flush").post(api::cache::flush))
+        .push(Router::with_path("/state/export").post(api::states::export))
+        .push(Router::with_path("/state/import").post(api::states::import))
+        .push(Router::with_path("/requests/<id>/cancel").post(api::request::cancel))
+        .push(Router::with_path("/sessions").post(api::session::create))
+        .push(
+            Router::with_path("/sessions/<id>")
+                .get(api::session::info)
+                .delete(api::session::delete),
+        )
+        .push(Router::with_path("/sessions/<id>/fork").post(api::session::fork))
+        .push(Router::with_path("/sessions/<id>/completions").post(api::session::completions))
         .push(Router::with_path("/files/unzip").post(api::unzip))
         .push(Router::with_path("/files/dir").post(api::dir))
         .push(Router::with_path("/files/ls").post(api::dir))
@@ -214,6 +255,8 @@ async fn main() {
             affix::inject(ThreadState {
                 sender,
                 model_path: config.model.model_path,
+                models,
+                names,
             })
             .insert("listen", listen.clone()),
         )
@@ -1,12 +1,12 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     convert::Infallible,
     fs::File,
     io::{BufReader, Read},
     mem,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Weak},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Result};
@@ -19,7 +19,8 @@ use safetensors::SafeTensors;
 
 use salvo::oapi::{ToResponse, ToSchema};
 use serde::{de::DeserializeSeed, Deserialize, Serialize};
-use tokio::sync::{Mutex, RwLock};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, Notify, RwLock};
 use web_rwkv::{
     context::{Context, ContextBuilder, Instance},
     model::{
@@ -33,19 +34,27 @@ use web_rwkv::{
 };
 
 use crate::{
+    cache::DiskCache,
     config::AdapterOption,
-    run::{GenerateContext, Runner, Runtime, SlotResult, Tokens},
-    sampler::{nucleus::NucleusSampler, Sampler},
+    queue::Queue,
+    run::{request_id, GenerateContext, Runner, Runtime, SlotResult, StateName, Tokens},
+    sampler::{grammar::Grammar, nucleus::NucleusSampler, Sampler},
 };
 
 pub const MAX_TOKENS: usize = 4096;
+pub const MAX_TOP_LOGPROBS: usize = 20;
 
 #[derive(Debug)]
 pub enum Token {
+    /// The request waits in the queue at this position, starting from 1.
+    Queued(usize),
     Start,
     Content(String),
+    Logprob(TokenLogprob),
     Stop(FinishReason, TokenCounter),
     Embed(Vec<f32>),
+    /// The backed state after the prompt, in safetensors.
+    State(Vec<u8>),
     Done,
 }
 
@@ -57,13 +66,35 @@ pub enum FinishReason {
     Stop,
     /// Incomplete model output due to max_tokens parameter or token limit.
     Length,
+    /// The model called one or more tools.
+    ToolCalls,
     /// Omitted content due to a flag from our content filters.
     ContentFilter,
+    /// Incomplete model output because the request runs out of time.
+    Timeout,
+    /// Incomplete model output because the request is cancelled.
+    Cancelled,
     /// API response still in progress or incomplete.
     #[default]
     Null,
 }
 
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+    /// The most likely tokens at this position.
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(untagged)]
 pub enum Array<T: ToSchema + 'static> {
@@ -98,24 +129,122 @@ pub enum ThreadRequest {
         tokenizer: Arc<Tokenizer>,
         sender: Sender<Token>,
     },
+    /// Request the server to generate multiple choices for the same prompt.
+    /// The prompt is prefilled only once and shared among all choices.
+    Fork {
+        requests: Vec<(GenerateRequest, Sender<Token>)>,
+        tokenizer: Arc<Tokenizer>,
+    },
     /// Reload the runtime with custom config.
+    /// Requests in flight finish on the old runtime, which holds on to its memory until then.
     Reload {
         request: Box<ReloadRequest>,
         sender: Option<Sender<bool>>,
     },
-    /// Unload the runtime.
+    /// Unload the runtime once the requests in flight finish.
     Unload,
     /// Save the current model with config.
     Save {
         request: SaveRequest,
         sender: Sender<bool>,
     },
+    /// List states in the memory cache.
+    CacheList(Sender<Vec<CacheItemInfo>>),
+    /// Report how well the cache works.
+    CacheStats(Sender<CacheStats>),
+    /// Remove cached states that start with the prefix, or all of them if there is no prefix.
+    CacheFlush {
+        prefix: Option<String>,
+        sender: Sender<usize>,
+    },
+    /// Add or replace an initial state with one in safetensors.
+    ImportState {
+        name: String,
+        data: Vec<u8>,
+        /// The `sid` of the caller, who owns the imported state under tenant isolation.
+        tenant: Option<String>,
+        sender: Sender<bool>,
+    },
+    /// Create, inspect, fork or delete a named session.
+    Session {
+        request: SessionRequest,
+        /// The `sid` of the caller, who may only use its own sessions under tenant isolation.
+        tenant: Option<String>,
+        sender: Sender<Result<SessionInfo, SessionError>>,
+    },
+    /// Cancel a request, whether it is queued or running. Replies `false` if there is no such request.
+    /// Only the tenant that sends a request may cancel it.
+    Cancel {
+        id: String,
+        tenant: Option<String>,
+        sender: Sender<bool>,
+    },
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CacheItemInfo {
+    /// Name of the initial state that the cached state starts from.
+    pub state: Option<String>,
+    /// Identity of the tenant that owns the cached state, if tenants are isolated.
+    pub tenant: Option<String>,
+    /// Number of tokens that the state has seen.
+    pub len: usize,
+    /// Text of the leading tokens.
+    pub preview: String,
+    /// Time of the last access, in seconds since the Unix epoch.
+    pub last_access: u64,
+    /// Number of times that the state is checked out.
+    pub hits: usize,
+    /// Whether the state is never evicted.
+    pub pinned: bool,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CacheStats {
+    /// Number of checkouts that find the whole input cached.
+    pub hits: usize,
+    /// Number of checkouts that find part of the input cached.
+    pub partial_hits: usize,
+    /// Number of checkouts that find nothing cached.
+    pub misses: usize,
+    /// Number of states in the memory cache.
+    pub items: usize,
+    /// Number of states that the memory budget allows.
+    pub max_items: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionRequest {
+    /// Create an empty session.
+    Create,
+    /// Get the info of a session.
+    Info(String),
+    /// Copy a session into a new one, so that the conversation branches.
+    Fork(String),
+    /// Delete a session and release its state.
+    Delete(String),
+}
+
+#[derive(Debug, Default, Clone, Serialize, ToSchema, ToResponse)]
+pub struct SessionInfo {
+    /// ID of the session.
+    pub id: String,
+    /// Number of tokens in the conversation so far.
+    pub num_tokens: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    /// There is no such session.
+    NotFound,
+    /// There are already as many sessions as allowed.
+    Full,
 }
 
 #[derive(Default)]
 pub enum Environment {
     Loaded {
-        runtime: Box<dyn Runner + Send + Sync>,
+        runtime: Arc<dyn Runner + Send + Sync>,
         reload: Box<ReloadRequest>,
     },
     #[default]
@@ -139,6 +268,38 @@ impl Environment {
         };
         queue
     }
+
+    /// Prepend the token history of the session that the context continues, if any.
+    pub async fn resume(&self, mut context: GenerateContext) -> GenerateContext {
+        let (Environment::Loaded { runtime, .. }, Some(id)) = (self, &context.request.session)
+        else {
+            return context;
+        };
+        if let Some(history) = runtime
+            .session(id.clone(), context.request.tenant.clone())
+            .await
+        {
+            let suffix = mem::take(&mut context.suffix);
+            context.suffix = Tokens([history, suffix.0].concat());
+        }
+        context
+    }
+
+    /// Default time limit of requests.
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            Environment::Loaded { reload, .. } => reload.scheduler.timeout(),
+            Environment::None => None,
+        }
+    }
+
+    /// Tokens exempted from penalties by default, which are computed once per tokenizer.
+    pub fn penalty_free_tokens(&self) -> Arc<HashSet<u16>> {
+        match self {
+            Environment::Loaded { runtime, .. } => runtime.penalty_free_tokens(),
+            Environment::None => Default::default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -146,16 +307,58 @@ pub struct RuntimeInfo {
     pub reload: ReloadRequest,
     pub model: ModelInfo,
     pub tokenizer: Arc<Tokenizer>,
+    /// Names of initial states, both listed in the config and imported, each with the tenant that owns it if any.
+    pub states: Vec<StateName>,
+    /// Number of requests waiting for a free slot.
+    pub queue_len: usize,
+}
+
+impl RuntimeInfo {
+    /// Check if the initial state selected by a request exists and is visible to the caller. The zero state is always there.
+    pub fn has_state(&self, name: Option<&str>, tenant: Option<&str>) -> bool {
+        match name {
+            Some(name) => self.states.iter().any(|(state, owner)| {
+                state == name && (owner.is_none() || owner.as_deref() == tenant)
+            }),
+            None => true,
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct AdapterList(pub Vec<String>);
 
+/// Requests of a higher priority class are always scheduled before those of a lower one.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Someone is waiting for the response, e.g., a chat.
+    #[default]
+    Interactive,
+    /// Nobody is waiting, e.g., offline data processing.
+    Batch,
+}
+
 #[derive(Clone, Derivative)]
 #[derivative(Debug, Default)]
 pub struct GenerateRequest {
     /// The prompt for the model.
     pub prompt: String,
+    /// The prompt given as token ids, which are fed into the model as is. Overrides `prompt` if set.
+    pub prompt_tokens: Option<Vec<u16>>,
     /// All text the model output earlier.
     pub model_text: String,
     /// Output token limit.
@@ -164,26 +367,56 @@ pub struct GenerateRequest {
     pub stop: Vec<String>,
     /// Bias added to tokens before sampling.
     pub bias: Arc<HashMap<u16, f32>>,
+    /// Number of most likely tokens to return log probabilities for at each position.
+    /// Log probabilities are not returned if this is `None`.
+    pub logprobs: Option<usize>,
     /// Sampler parameters.
     #[derivative(
         Debug = "ignore",
         Default(value = "Arc::new(RwLock::new(NucleusSampler::default()))")
     )]
     pub sampler: Arc<RwLock<dyn Sampler + Send + Sync>>,
+    /// Grammar that the output must conform to.
+    pub grammar: Option<Arc<RwLock<Grammar>>>,
     /// Whether this is an embedding request.
     pub embed: bool,
     /// The (reversed) number of layer at which the output is as embedding.
     pub embed_layer: usize,
+    /// Whether to send back the state after the prompt.
+    pub export: bool,
+    /// Whether to only read the prompt and back its state, without sampling any token.
+    pub prefill: bool,
+    /// Whether to pin the state after generation in the cache, so that it is never evicted.
+    pub pin: bool,
+    /// The session to continue. Its state and token history are updated after generation.
+    pub session: Option<String>,
+    /// Name of the initial state to start from. The zero state is used if this is `None`.
+    pub state: Option<String>,
+    /// Identity of the caller. Callers never share cached states if tenants are isolated.
+    pub tenant: Option<String>,
+    /// Priority class of the request. Falls back to that of the caller's key, then to interactive.
+    pub priority: Option<Priority>,
+    /// Share of the slots that the caller gets relative to other callers when the server is busy.
+    #[derivative(Default(value = "1"))]
+    pub weight: usize,
+    /// Time limit of the request, from its arrival to its end. Falls back to the server default.
+    pub timeout: Option<Duration>,
+    /// ID to cancel the request by. A new one is assigned if not set.
+    pub id: Option<String>,
 }
 
 #[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct ReloadRequest {
+    /// Name that requests select the model by. Defaults to the file name of the model without extension.
+    pub name: Option<String>,
     /// Path to the model.
     pub model_path: PathBuf,
     /// List of LoRA blended on the model.
     pub lora: Vec<crate::config::Lora>,
+    /// Initial states that requests may start from instead of the zero state.
+    pub state: Vec<crate::config::InitState>,
     /// Specify layers that needs to be quantized.
     pub quant: usize,
     /// Quantization type (Int8 or NF4).
@@ -207,10 +440,78 @@ pub struct ReloadRequest {
     pub embed_device: EmbedDevice,
     /// Path to the tokenizer.
     pub tokenizer_path: PathBuf,
+    /// Tokens containing any of these are exempted from presence and frequency penalties.
+    #[derivative(Default(value = "crate::config::default_penalty_free_list()"))]
+    pub penalty_free_list: Vec<String>,
+    /// State cache options.
+    pub cache: crate::config::Cache,
+    /// Queueing and scheduling options.
+    pub scheduler: crate::config::Scheduler,
     /// Adapter selection.
     pub adapter: AdapterOption,
 }
 
+impl ReloadRequest {
+    /// Name that requests select the model by.
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .model_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// A fingerprint of the configuration that affects model outputs.
+    /// Files are identified by their sizes and modification times too, so that replacing a file in place changes the fingerprint.
+    pub fn fingerprint(&self) -> String {
+        fn hash_file(path: &Path, sha: &mut Sha256) {
+            sha.update(path.to_string_lossy().as_bytes());
+            if let Ok(meta) = std::fs::metadata(path) {
+                sha.update(meta.len().to_le_bytes());
+                let modified = meta
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|time| time.as_nanos())
+                    .unwrap_or_default();
+                sha.update(modified.to_le_bytes());
+            }
+        }
+
+        let mut sha = Sha256::new();
+        hash_file(&self.model_path, &mut sha);
+        sha.update(self.quant.to_le_bytes());
+        sha.update(format!("{:?}", self.quant_type));
+        for lora in &self.lora {
+            hash_file(&lora.path, &mut sha);
+            sha.update(lora.alpha.to_le_bytes());
+        }
+        digest(sha)
+    }
+
+    /// Name of the disk cache directory of the model.
+    /// Besides the fingerprint, it covers the layout of the states, since a state only loads into a model of the same shape.
+    pub fn disk_key(&self, info: &ModelInfo) -> String {
+        let mut sha = Sha256::new();
+        sha.update(self.fingerprint());
+        sha.update(format!("{:?}", info.version));
+        sha.update(info.num_layer.to_le_bytes());
+        sha.update(info.num_emb.to_le_bytes());
+        sha.update(self.state_chunk_size.to_le_bytes());
+        digest(sha)
+    }
+}
+
+fn digest(sha: Sha256) -> String {
+    let hash = sha.finalize();
+    let mut head = [0; 8];
+    head.copy_from_slice(&hash[..8]);
+    format!("fp_{:016x}", u64::from_be_bytes(head))
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct SaveRequest {
@@ -236,10 +537,42 @@ pub struct TokenCounter {
     pub total_tokens: usize,
 }
 
+impl TokenCounter {
+    /// Aggregate the usage of choices that share the same prompt.
+    pub fn merge(counters: impl IntoIterator<Item = TokenCounter>) -> Self {
+        let mut merged = TokenCounter::default();
+        for counter in counters {
+            merged.prompt_tokens = counter.prompt_tokens;
+            merged.completion_tokens += counter.completion_tokens;
+        }
+        merged.total_tokens = merged.prompt_tokens + merged.completion_tokens;
+        merged
+    }
+
+    /// Aggregate the usage of requests with independent prompts.
+    pub fn sum(counters: impl IntoIterator<Item = TokenCounter>) -> Self {
+        let mut sum = TokenCounter::default();
+        for counter in counters {
+            sum.prompt_tokens += counter.prompt_tokens;
+            sum.completion_tokens += counter.completion_tokens;
+        }
+        sum.total_tokens = sum.prompt_tokens + sum.completion_tokens;
+        sum
+    }
+}
+
+/// Threads of the loaded models by their names, which the threads keep up to date as they load and unload models.
+pub type ModelNames = Arc<RwLock<HashMap<String, Sender<ThreadRequest>>>>;
+
 #[derive(Clone)]
 pub struct ThreadState {
+    /// Sender to the thread of the main model.
     pub sender: Sender<ThreadRequest>,
     pub model_path: PathBuf,
+    /// Senders to the threads of other hosted models.
+    pub models: Vec<Sender<ThreadRequest>>,
+    /// Threads of all loaded models, including the main one, by their names.
+    pub names: ModelNames,
 }
 
 fn list_adapters() -> AdapterList {
@@ -268,12 +601,32 @@ async fn create_context(adapter: AdapterOption, info: &ModelInfo) -> Result<Cont
     Ok(context)
 }
 
-fn load_tokenizer(path: impl AsRef<Path>) -> Result<Tokenizer> {
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VocabWord {
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+/// Bytes of each token in the vocabulary.
+type Vocabulary = Vec<(u16, Vec<u8>)>;
+
+/// Load the tokenizer, along with its vocabulary.
+fn load_tokenizer(path: impl AsRef<Path>) -> Result<(Tokenizer, Vocabulary)> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
     let mut contents = String::new();
     reader.read_to_string(&mut contents)?;
-    Ok(Tokenizer::new(&contents)?)
+    let tokenizer = Tokenizer::new(&contents)?;
+    let vocab: BTreeMap<u16, VocabWord> = serde_json::from_str(&contents)?;
+    let vocab = vocab
+        .into_iter()
+        .map(|(token, word)| match word {
+            VocabWord::Str(word) => (token, word.into_bytes()),
+            VocabWord::Bytes(word) => (token, word),
+        })
+        .collect();
+    Ok((tokenizer, vocab))
 }
 
 async fn load_model<M, S>(
@@ -354,35 +707,129 @@ where
     Ok((model, state))
 }
 
+async fn create_generate_context(
+    request: GenerateRequest,
+    tokenizer: &Tokenizer,
+    penalty_free_tokens: Arc<HashSet<u16>>,
+    sender: Sender<Token>,
+) -> Result<GenerateContext> {
+    let tokens = match &request.prompt_tokens {
+        Some(tokens) => {
+            // reject token ids that are out of the vocabulary
+            tokenizer.decode(tokens)?;
+            Tokens(tokens.clone())
+        }
+        None => Tokens(tokenizer.encode(request.prompt.as_bytes())?),
+    };
+    let model_tokens = Tokens(tokenizer.encode(request.model_text.as_bytes())?);
+    // init sampler state here
+    request
+        .sampler
+        .write()
+        .await
+        .init(&model_tokens, penalty_free_tokens);
+
+    let id = request.id.clone().unwrap_or_else(request_id);
+    let deadline = request.timeout.map(|timeout| Instant::now() + timeout);
+    Ok(GenerateContext {
+        id,
+        prompt_tokens: tokens.to_vec(),
+        prompt_cached: false,
+        prefix: Default::default(),
+        suffix: tokens,
+        model_text: Default::default(),
+        buffer: Default::default(),
+        model_tokens: Default::default(),
+        request,
+        sender,
+        deadline,
+    })
+}
+
+/// Wake the queue when the context expires, so that it is dropped from the queue even if no slot becomes idle meanwhile.
+fn wake_on_expiry(context: &GenerateContext, idle: Arc<Notify>) {
+    if let Some(deadline) = context.deadline {
+        tokio::spawn(async move {
+            tokio::time::sleep_until(deadline.into()).await;
+            idle.notify_one();
+        });
+    }
+}
+
+/// Prefill configured prompts, whose states are then pinned in the cache.
+async fn warmup_cache(
+    env: Arc<RwLock<Environment>>,
+    queue: Arc<Mutex<Queue>>,
+    sender: Sender<()>,
+    warmup: Vec<crate::config::Warmup>,
+) -> Result<()> {
+    let (tokenizer, penalty_free_tokens) = match &*env.read().await {
+        Environment::Loaded { runtime, .. } => (runtime.tokenizer(), runtime.penalty_free_tokens()),
+        Environment::None => return Ok(()),
+    };
+    for crate::config::Warmup { prompt } in warmup {
+        let request = GenerateRequest {
+            prompt,
+            max_tokens: 0,
+            pin: true,
+            priority: Some(Priority::Batch),
+            ..Default::default()
+        };
+        // nobody listens to the output, so the context finishes right after the prompt
+        let (token_sender, _) = flume::unbounded();
+        let context = create_generate_context(
+            request,
+            &tokenizer,
+            penalty_free_tokens.clone(),
+            token_sender,
+        )
+        .await?;
+
+        let mut queue = queue.lock().await;
+        queue.push(context);
+        queue.dispatch(&*env.read().await).await;
+        let _ = sender.send(());
+    }
+    Ok(())
+}
+
 #[tokio::main]
-pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
+/// Serve the requests to a model. `thread` is the sender of `receiver`, by which the model is listed in `names` once loaded.
+pub async fn model_route(
+    receiver: Receiver<ThreadRequest>,
+    thread: Sender<ThreadRequest>,
+    names: ModelNames,
+) -> Result<()> {
     let env: Arc<RwLock<Environment>> = Default::default();
-    let queue: Arc<Mutex<Vec<GenerateContext>>> = Default::default();
+    let queue: Arc<Mutex<Queue>> = Default::default();
+    let idle: Arc<Notify> = Default::default();
 
-    let sender = {
+    let (sender, retire) = {
         let (sender, receiver) = flume::unbounded();
+        let (retire, retired) = flume::unbounded();
         let env = env.clone();
-        tokio::task::spawn_blocking(move || crate::run::run(receiver, env));
-        sender
+        tokio::task::spawn_blocking(move || crate::run::run(receiver, retired, env));
+        (sender, retire)
     };
+    // retired runtimes that may still run requests, which can be cancelled there
+    // the run loop holds the only strong references, so these are gone once their requests finish
+    let retired: Arc<Mutex<Vec<Weak<dyn Runner + Send + Sync>>>> = Default::default();
 
     let dequeue = {
         let env = env.clone();
         let queue = queue.clone();
         let sender = sender.clone();
+        let idle = idle.clone();
 
         async move {
             loop {
+                // a notification is kept if nobody waits, so slots freed during a dispatch are not missed
+                idle.notified().await;
+
                 let mut queue = queue.lock().await;
-                let mut temp = vec![];
-                for context in queue.drain(..) {
-                    temp.append(&mut env.read().await.enqueue(context).await);
+                if queue.dispatch(&*env.read().await).await > 0 {
                     let _ = sender.send(());
                 }
-                std::mem::swap(&mut *queue, &mut temp);
-                drop(queue);
-
-                tokio::time::sleep(Duration::from_secs(1)).await;
             }
         }
     };
@@ -398,16 +845,21 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                 }
                 ThreadRequest::Info(sender) => {
                     let env = env.clone();
+                    let queue = queue.clone();
                     tokio::spawn(async move {
+                        let queue_len = queue.lock().await.len();
                         let env = &(*env.read().await);
                         if let Environment::Loaded { runtime, reload } = env {
                             let reload = reload.as_ref().clone();
                             let model = runtime.info().clone();
                             let tokenizer = runtime.tokenizer();
+                            let states = runtime.states().await;
                             let _ = sender.send(RuntimeInfo {
                                 reload,
                                 model,
                                 tokenizer,
+                                states,
+                                queue_len,
                             });
                         }
                     });
@@ -419,6 +871,12 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                     let request = *request;
                     let sender = sender.clone();
                     let env = env.clone();
+                    let queue = queue.clone();
+                    let idle = idle.clone();
+                    let retire = retire.clone();
+                    let retired = retired.clone();
+                    let thread = thread.clone();
+                    let names = names.clone();
                     let reload = async move {
                         let sender = sender.clone();
 
@@ -438,13 +896,21 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                         log::info!("type: {:?}", load_type);
 
                         let context = create_context(request.adapter, &info).await?;
-                        let tokenizer = load_tokenizer(&request.tokenizer_path)?;
+                        let (tokenizer, vocab) = load_tokenizer(&request.tokenizer_path)?;
                         log::info!("{:#?}", context.adapter.get_info());
 
-                        let mut env = env.write().await;
-                        drop(mem::take(&mut *env));
+                        // states on disk are only valid for the exact same model config
+                        let disk = match &request.cache.disk_path {
+                            Some(path) => {
+                                let path = path.join(request.disk_key(&info));
+                                let budget = request.cache.disk_budget as u64 * 1024 * 1024;
+                                Some(DiskCache::new(path, budget)?)
+                            }
+                            None => None,
+                        };
 
-                        let runtime: Box<dyn Runner + Send + Sync> = match info.version {
+                        // the new runtime is built while the old one keeps serving, so a failed load changes nothing
+                        let runtime: Arc<dyn Runner + Send + Sync> = match info.version {
                             ModelVersion::V4 => {
                                 let (model, state) = load_model::<v4::Model<f16>, _>(
                                     &context,
@@ -452,13 +918,15 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                                     load_type,
                                 )
                                 .await?;
-                                Box::new(Runtime::new(
+                                Arc::new(Runtime::new(
                                     tokenizer,
+                                    &vocab,
                                     model,
                                     state,
-                                    request.max_runtime_batch,
-                                    request.state_chunk_size,
-                                ))
+                                    &request,
+                                    disk,
+                                    idle.clone(),
+                                )?)
                             }
                             ModelVersion::V5 => {
                                 let (model, state) = load_model::<v5::Model<f16>, _>(
@@ -467,13 +935,15 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                                     load_type,
                                 )
                                 .await?;
-                                Box::new(Runtime::new(
+                                Arc::new(Runtime::new(
                                     tokenizer,
+                                    &vocab,
                                     model,
                                     state,
-                                    request.max_runtime_batch,
-                                    request.state_chunk_size,
-                                ))
+                                    &request,
+                                    disk,
+                                    idle.clone(),
+                                )?)
                             }
                             ModelVersion::V6 => {
                                 let (model, state) = load_model::<v6::Model<f16>, _>(
@@ -482,19 +952,46 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                                     load_type,
                                 )
                                 .await?;
-                                Box::new(Runtime::new(
+                                Arc::new(Runtime::new(
                                     tokenizer,
+                                    &vocab,
                                     model,
                                     state,
-                                    request.max_runtime_batch,
-                                    request.state_chunk_size,
-                                ))
+                                    &request,
+                                    disk,
+                                    idle.clone(),
+                                )?)
                             }
                         };
+                        let warmup = request.cache.warmup.clone();
+                        let name = request.name();
                         let reload = Box::new(request);
-                        *env = Environment::Loaded { runtime, reload };
+                        let env_old = {
+                            let mut guard = env.write().await;
+                            mem::replace(&mut *guard, Environment::Loaded { runtime, reload })
+                        };
+
+                        // requests find the model by its new name from now on
+                        {
+                            let mut names = names.write().await;
+                            names.retain(|_, other| !other.same_channel(&thread));
+                            names.insert(name, thread);
+                        }
+
+                        // requests in flight on the old runtime finish there before it is dropped
+                        if let Environment::Loaded { runtime, .. } = env_old {
+                            retired.lock().await.push(Arc::downgrade(&runtime));
+                            let _ = retire.send(runtime);
+                        }
+
+                        // requests may have piled up while there was no model
+                        idle.notify_one();
 
                         let _ = sender.send(());
+                        if let Err(err) = warmup_cache(env, queue, sender, warmup).await {
+                            log::warn!("failed to warm up cache: {}", err);
+                        }
+
                         anyhow::Ok(())
                     };
                     let callback = move |result: bool| {
@@ -517,9 +1014,22 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                 }
                 ThreadRequest::Unload => {
                     let env = env.clone();
+                    let sender = sender.clone();
+                    let retire = retire.clone();
+                    let retired = retired.clone();
+                    let thread = thread.clone();
+                    let names = names.clone();
                     tokio::spawn(async move {
-                        let mut env = env.write().await;
-                        *env = Environment::None;
+                        names
+                            .write()
+                            .await
+                            .retain(|_, other| !other.same_channel(&thread));
+                        let env_old = mem::take(&mut *env.write().await);
+                        if let Environment::Loaded { runtime, .. } = env_old {
+                            retired.lock().await.push(Arc::downgrade(&runtime));
+                            let _ = retire.send(runtime);
+                            let _ = sender.send(());
+                        }
                         log::info!("model unloaded");
                     });
                 }
@@ -528,33 +1038,250 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                     tokenizer,
                     sender: token_sender,
                 } => {
-                    let request = *request;
-                    let tokens = Tokens(tokenizer.encode(request.prompt.as_bytes())?);
-                    let model_tokens = Tokens(tokenizer.encode(request.model_text.as_bytes())?);
-                    // init sampler state here
-                    request.sampler.write().await.init(&model_tokens);
-
-                    let context = GenerateContext {
-                        prompt_tokens: tokens.to_vec(),
-                        prompt_cached: false,
-                        prefix: Default::default(),
-                        suffix: tokens,
-                        model_text: Default::default(),
-                        buffer: Default::default(),
-                        model_tokens: Default::default(),
-                        request,
-                        sender: token_sender,
+                    let penalty_free_tokens = env.read().await.penalty_free_tokens();
+                    let timeout = request.timeout.or(env.read().await.timeout());
+                    let request = GenerateRequest {
+                        timeout,
+                        ..*request
                     };
+                    let context = create_generate_context(
+                        request,
+                        &tokenizer,
+                        penalty_free_tokens,
+                        token_sender,
+                    )
+                    .await?;
+                    let context = env.read().await.resume(context).await;
+                    wake_on_expiry(&context, idle.clone());
 
                     let env = env.clone();
                     let queue = queue.clone();
                     let sender = sender.clone();
                     tokio::spawn(async move {
                         let mut queue = queue.lock().await;
-                        queue.append(&mut env.read().await.enqueue(context).await);
+                        queue.push(context);
+                        queue.dispatch(&*env.read().await).await;
                         let _ = sender.send(());
                     });
                 }
+                ThreadRequest::Fork {
+                    requests,
+                    tokenizer,
+                } => {
+                    let penalty_free_tokens = env.read().await.penalty_free_tokens();
+                    let timeout = env.read().await.timeout();
+                    let mut contexts = vec![];
+                    for (request, token_sender) in requests {
+                        let penalty_free_tokens = penalty_free_tokens.clone();
+                        let request = GenerateRequest {
+                            timeout: request.timeout.or(timeout),
+                            ..request
+                        };
+                        let context = create_generate_context(
+                            request,
+                            &tokenizer,
+                            penalty_free_tokens,
+                            token_sender,
+                        )
+                        .await?;
+                        let context = env.read().await.resume(context).await;
+                        wake_on_expiry(&context, idle.clone());
+                        contexts.push(context);
+                    }
+                    let prompt_tokens = contexts
+                        .first()
+                        .map(|context| context.suffix.to_vec())
+                        .unwrap_or_default();
+
+                    let env = env.clone();
+                    let queue = queue.clone();
+                    let sender = sender.clone();
+                    tokio::spawn(async move {
+                        // prefill all but the last token of the prompt once, so that every choice
+                        // checks out the backed state and only needs to infer the last token by itself
+                        if contexts.len() > 1 && prompt_tokens.len() > 1 {
+                            let tokens = prompt_tokens[..prompt_tokens.len() - 1].to_vec();
+                            let (prefill_sender, prefill_receiver) = flume::unbounded();
+                            let context = GenerateContext {
+                                id: contexts[0].id.clone(),
+                                prompt_tokens: tokens.clone(),
+                                prompt_cached: true,
+                                prefix: Default::default(),
+                                suffix: Tokens(tokens),
+                                model_text: Default::default(),
+                                buffer: Default::default(),
+                                model_tokens: Default::default(),
+                                // the prefill must land in the same cache partition as the choices
+                                request: GenerateRequest {
+                                    max_tokens: 0,
+                                    prefill: true,
+                                    state: contexts[0].request.state.clone(),
+                                    tenant: contexts[0].request.tenant.clone(),
+                                    priority: contexts[0].request.priority,
+                                    weight: contexts[0].request.weight,
+                                    ..Default::default()
+                                },
+                                sender: prefill_sender,
+                                deadline: contexts[0].deadline,
+                            };
+
+                            let mut queue = queue.lock().await;
+                            queue.push(context);
+                            queue.dispatch(&*env.read().await).await;
+                            let _ = sender.send(());
+                            drop(queue);
+
+                            // the prefill context is dropped only after its state is backed into the cache
+                            let mut reason = FinishReason::Null;
+                            while let Ok(token) = prefill_receiver.recv_async().await {
+                                if let Token::Stop(finish_reason, _) = token {
+                                    reason = finish_reason;
+                                }
+                            }
+
+                            // choices share the fate of the prefill if it is cancelled, runs out of time or fails
+                            // a prefill that is dropped without finishing leaves `reason` as null
+                            if !matches!(reason, FinishReason::Stop) {
+                                log::warn!(
+                                    "prefill of request {} ended with {:?}",
+                                    contexts[0].id,
+                                    reason
+                                );
+                                for context in contexts {
+                                    context.stop(reason);
+                                }
+                                return;
+                            }
+                        }
+
+                        let mut queue = queue.lock().await;
+                        for context in contexts {
+                            queue.push(context);
+                        }
+                        queue.dispatch(&*env.read().await).await;
+                        let _ = sender.send(());
+                    });
+                }
+                ThreadRequest::CacheList(sender) => {
+                    let env = env.clone();
+                    tokio::spawn(async move {
+                        let env = &(*env.read().await);
+                        if let Environment::Loaded { runtime, .. } = env {
+                            let _ = sender.send(runtime.cache_items().await);
+                        }
+                    });
+                }
+                ThreadRequest::CacheStats(sender) => {
+                    let env = env.clone();
+                    tokio::spawn(async move {
+                        let env = &(*env.read().await);
+                        if let Environment::Loaded { runtime, .. } = env {
+                            let _ = sender.send(runtime.cache_stats().await);
+                        }
+                    });
+                }
+                ThreadRequest::CacheFlush { prefix, sender } => {
+                    let env = env.clone();
+                    tokio::spawn(async move {
+                        let env = &(*env.read().await);
+                        if let Environment::Loaded { runtime, .. } = env {
+                            match runtime.flush_cache(prefix).await {
+                                Ok(count) => {
+                                    let _ = sender.send(count);
+                                }
+                                Err(err) => log::error!("failed to flush cache: {}", err),
+                            }
+                        }
+                    });
+                }
+                ThreadRequest::ImportState {
+                    name,
+                    data,
+                    tenant,
+                    sender,
+                } => {
+                    let env = env.clone();
+                    tokio::spawn(async move {
+                        let env = &(*env.read().await);
+                        if let Environment::Loaded { runtime, .. } = env {
+                            let _ = match runtime.import_state(name, data, tenant).await {
+                                Ok(()) => sender.send(true),
+                                Err(err) => {
+                                    log::error!("failed to import state: {}", err);
+                                    sender.send(false)
+                                }
+                            };
+                        }
+                    });
+                }
+                ThreadRequest::Cancel { id, tenant, sender } => {
+                    let env = env.clone();
+                    let queue = queue.clone();
+                    let retired = retired.clone();
+                    tokio::spawn(async move {
+                        let mut queue = queue.lock().await;
+                        let queued = queue.cancel(&id, &tenant);
+                        let mut running = match &*env.read().await {
+                            Environment::Loaded { runtime, .. } => {
+                                runtime.cancel(id.clone(), tenant.clone()).await
+                            }
+                            Environment::None => false,
+                        };
+
+                        // the request may have started before a reload swapped the runtime
+                        let runtimes = {
+                            let mut retired = retired.lock().await;
+                            retired.retain(|runtime| runtime.strong_count() > 0);
+                            retired.iter().filter_map(Weak::upgrade).collect_vec()
+                        };
+                        for runtime in runtimes {
+                            running |= runtime.cancel(id.clone(), tenant.clone()).await;
+                        }
+                        let _ = sender.send(queued > 0 || running);
+                    });
+                }
+                ThreadRequest::Session {
+                    request,
+                    tenant,
+                    sender,
+                } => {
+                    let env = env.clone();
+                    tokio::spawn(async move {
+                        let env = &(*env.read().await);
+                        if let Environment::Loaded { runtime, .. } = env {
+                            let session = match request {
+                                SessionRequest::Create => {
+                                    runtime.create_session(tenant).await.map(|id| (id, vec![]))
+                                }
+                                SessionRequest::Info(id) => runtime
+                                    .session(id.clone(), tenant)
+                                    .await
+                                    .map(|x| (id, x))
+                                    .ok_or(SessionError::NotFound),
+                                SessionRequest::Fork(id) => {
+                                    match runtime.fork_session(id, tenant.clone()).await {
+                                        Ok(id) => runtime
+                                            .session(id.clone(), tenant)
+                                            .await
+                                            .map(|x| (id, x))
+                                            .ok_or(SessionError::NotFound),
+                                        Err(err) => Err(err),
+                                    }
+                                }
+                                SessionRequest::Delete(id) => runtime
+                                    .delete_session(id.clone(), tenant)
+                                    .await
+                                    .map(|x| (id, x))
+                                    .ok_or(SessionError::NotFound),
+                            };
+                            let info = session.map(|(id, history)| SessionInfo {
+                                id,
+                                num_tokens: history.len(),
+                            });
+                            let _ = sender.send(info);
+                        }
+                    });
+                }
                 ThreadRequest::Save { request, sender } => {
                     let env = env.clone();
                     tokio::spawn(async move {
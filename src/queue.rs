@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+use crate::{
+    middleware::{Environment, FinishReason, GenerateRequest, Token},
+    run::GenerateContext,
+};
+
+/// Virtual time of callers, by which their requests take turns.
+#[derive(Debug, Default, Clone)]
+struct Clocks {
+    /// Virtual time of each caller, which advances by the inverse of its weight whenever one of its requests is admitted.
+    clocks: HashMap<Option<String>, f64>,
+    /// Virtual time of the last admitted request.
+    /// Callers that have been idle start from here, so that they cannot save up credit.
+    now: f64,
+}
+
+impl Clocks {
+    /// Virtual time at which the next request of the caller is due.
+    fn start(&self, tenant: &Option<String>) -> f64 {
+        let clock = self.clocks.get(tenant).copied().unwrap_or_default();
+        clock.max(self.now)
+    }
+
+    /// Pick the request to admit next among the candidates, which are given by their indices in the queue.
+    fn next<'a>(
+        &self,
+        candidates: impl IntoIterator<Item = (usize, &'a GenerateRequest)>,
+    ) -> Option<usize> {
+        candidates
+            .into_iter()
+            .min_by(|(x_index, x), (y_index, y)| {
+                let x_start = self.start(&x.tenant);
+                let y_start = self.start(&y.tenant);
+                x.priority
+                    .unwrap_or_default()
+                    .cmp(&y.priority.unwrap_or_default())
+                    .then(x_start.total_cmp(&y_start))
+                    .then(x_index.cmp(y_index))
+            })
+            .map(|(index, _)| index)
+    }
+
+    /// Charge the caller of an admitted request.
+    fn advance(&mut self, tenant: &Option<String>, weight: usize) {
+        let start = self.start(tenant);
+        let weight = weight.max(1) as f64;
+        self.clocks.insert(tenant.clone(), start + 1.0 / weight);
+        self.now = start;
+    }
+
+    /// Forget callers that are not ahead of the virtual time, since they start from it anyway.
+    fn prune(&mut self) {
+        let now = self.now;
+        self.clocks.retain(|_, clock| *clock > now);
+    }
+}
+
+struct Pending {
+    context: GenerateContext,
+    /// Position in the queue that is last reported to the caller, starting from 1.
+    position: usize,
+}
+
+/// Requests that wait for a free slot.
+///
+/// Requests of the interactive class are always admitted before batch ones.
+/// Within a class, callers share the slots in proportion to their weights (start-time fair queuing),
+/// so that a caller flooding the server only delays its own requests.
+#[derive(Default)]
+pub struct Queue {
+    pending: Vec<Pending>,
+    clocks: Clocks,
+}
+
+impl Queue {
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn push(&mut self, context: GenerateContext) {
+        self.pending.push(Pending {
+            context,
+            position: 0,
+        });
+    }
+
+    fn next(&self) -> Option<usize> {
+        let candidates = self
+            .pending
+            .iter()
+            .map(|pending| &pending.context.request)
+            .enumerate();
+        self.clocks.next(candidates)
+    }
+
+    /// Indices of the requests in the order that they are admitted, provided that no more requests arrive.
+    fn schedule(&self) -> Vec<usize> {
+        let mut clocks = self.clocks.clone();
+        let mut remain = (0..self.pending.len()).collect_vec();
+        let mut order = Vec::with_capacity(remain.len());
+        while let Some(index) = clocks.next(
+            remain
+                .iter()
+                .map(|&index| (index, &self.pending[index].context.request)),
+        ) {
+            let request = &self.pending[index].context.request;
+            clocks.advance(&request.tenant, request.weight);
+            remain.retain(|&x| x != index);
+            order.push(index);
+        }
+        order
+    }
+
+    /// Tell callers whose positions in the queue have changed.
+    fn report(&mut self) {
+        for (position, index) in self.schedule().into_iter().enumerate() {
+            let pending = &mut self.pending[index];
+            if pending.position != position + 1 {
+                pending.position = position + 1;
+                let _ = pending.context.sender.send(Token::Queued(position + 1));
+            }
+        }
+    }
+
+    /// Remove the contexts of a request sent by `tenant` from the queue, and return the number of them.
+    pub fn cancel(&mut self, id: &str, tenant: &Option<String>) -> usize {
+        let len = self.pending.len();
+        self.pending.retain(|pending| {
+            let context = &pending.context;
+            match context.id == id && context.request.tenant == *tenant {
+                true => {
+                    context.stop(FinishReason::Cancelled);
+                    false
+                }
+                false => true,
+            }
+        });
+        self.report();
+        len - self.pending.len()
+    }
+
+    /// Hand requests over to the runtime in schedule order until it runs out of slots.
+    /// Returns the number of admitted requests.
+    pub async fn dispatch(&mut self, env: &Environment) -> usize {
+        // requests that run out of time while waiting are finished without running at all
+        self.pending
+            .retain(|pending| match pending.context.is_expired() {
+                true => {
+                    pending.context.stop(FinishReason::Timeout);
+                    false
+                }
+                false => true,
+            });
+
+        let mut count = 0;
+        while let Some(index) = self.next() {
+            let Pending { context, position } = self.pending.remove(index);
+            let tenant = context.request.tenant.clone();
+            let weight = context.request.weight;
+
+            if let Some(context) = env.enqueue(context).await.pop() {
+                // all slots are occupied, so the request keeps its place
+                self.pending.insert(index, Pending { context, position });
+                break;
+            }
+
+            self.clocks.advance(&tenant, weight);
+            count += 1;
+        }
+
+        self.clocks.prune();
+        self.report();
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flume::Receiver;
+
+    use super::*;
+    use crate::middleware::Priority;
+
+    fn push(
+        queue: &mut Queue,
+        id: &str,
+        tenant: &str,
+        priority: Priority,
+        weight: usize,
+    ) -> Receiver<Token> {
+        let (sender, receiver) = flume::unbounded();
+        queue.push(GenerateContext {
+            id: id.into(),
+            prompt_tokens: vec![],
+            prompt_cached: false,
+            prefix: Default::default(),
+            suffix: Default::default(),
+            model_text: vec![],
+            buffer: vec![],
+            model_tokens: vec![],
+            request: GenerateRequest {
+                tenant: Some(tenant.into()),
+                priority: Some(priority),
+                weight,
+                ..Default::default()
+            },
+            sender,
+            deadline: None,
+        });
+        receiver
+    }
+
+    fn ids(queue: &Queue) -> Vec<&str> {
+        queue
+            .schedule()
+            .into_iter()
+            .map(|index| queue.pending[index].context.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn fair_share() {
+        let mut queue = Queue::default();
+        for id in ["a0", "a1", "a2", "a3"] {
+            push(&mut queue, id, "a", Priority::Interactive, 1);
+        }
+        for id in ["b0", "b1"] {
+            push(&mut queue, id, "b", Priority::Interactive, 1);
+        }
+        assert_eq!(ids(&queue), ["a0", "b0", "a1", "b1", "a2", "a3"]);
+
+        // a caller of twice the weight takes twice the turns
+        let mut queue = Queue::default();
+        for id in ["a0", "a1", "a2", "a3"] {
+            push(&mut queue, id, "a", Priority::Interactive, 2);
+        }
+        for id in ["b0", "b1"] {
+            push(&mut queue, id, "b", Priority::Interactive, 1);
+        }
+        assert_eq!(ids(&queue), ["a0", "b0", "a1", "a2", "b1", "a3"]);
+    }
+
+    #[test]
+    fn priority() {
+        let mut queue = Queue::default();
+        push(&mut queue, "a0", "a", Priority::Batch, 1);
+        push(&mut queue, "a1", "a", Priority::Batch, 1);
+        push(&mut queue, "b0", "b", Priority::Interactive, 1);
+        push(&mut queue, "a2", "a", Priority::Interactive, 1);
+        assert_eq!(ids(&queue), ["b0", "a2", "a0", "a1"]);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut queue = Queue::default();
+        let a = push(&mut queue, "a", "x", Priority::Interactive, 1);
+        let b = push(&mut queue, "b", "y", Priority::Interactive, 1);
+        let c = push(&mut queue, "c", "x", Priority::Interactive, 1);
+        queue.report();
+        let positions = |receiver: &Receiver<Token>| {
+            receiver
+                .drain()
+                .map(|token| match token {
+                    Token::Queued(position) => position,
+                    token => panic!("unexpected token {token:?}"),
+                })
+                .collect_vec()
+        };
+        assert_eq!(positions(&a), [1]);
+        assert_eq!(positions(&b), [2]);
+        assert_eq!(positions(&c), [3]);
+
+        // only the caller that sent the request may cancel it
+        assert_eq!(queue.cancel("a", &Some("y".into())), 0);
+        assert_eq!(queue.cancel("a", &Some("x".into())), 1);
+        assert_eq!(queue.len(), 2);
+        assert!(matches!(
+            a.drain().collect_vec()[..],
+            [Token::Stop(FinishReason::Cancelled, _), Token::Done]
+        ));
+
+        // callers are told only if their positions change
+        assert_eq!(positions(&b), [1]);
+        assert_eq!(positions(&c), [2]);
+        assert_eq!(queue.cancel("c", &Some("x".into())), 1);
+        assert!(positions(&b).is_empty());
+    }
+}
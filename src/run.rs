@@ -1,34 +1,44 @@
 use std::{
     borrow::Borrow,
     cmp::Ordering,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     convert::Infallible,
     future::Future,
     io::Write,
     path::PathBuf,
     pin::Pin,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Result;
 use flume::{Receiver, Sender};
 use itertools::Itertools;
 use qp_trie::Trie;
-use serde::Serialize;
-use tokio::sync::{Mutex, RwLock};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{Mutex, Notify, RwLock};
+use uuid::Uuid;
 use web_rwkv::{
     model::{
-        BackedState, Build, Model, ModelInfo, ModelInput, ModelOutput, ModelState, StateBuilder,
+        BackedState, Build, Model, ModelInfo, ModelInput, ModelOutput, ModelState, ModelVersion,
+        StateBuilder,
     },
     tokenizer::Tokenizer,
 };
 
-use crate::middleware::{Environment, FinishReason, GenerateRequest, Token, TokenCounter};
-
-const PENALTY_FREE_LIST: [&str; 5] = ["\n", ",", ".", "\u{002c}", "\u{002f}"];
-const PROMPT_CACHE_TOKENS: usize = 32;
-const MAX_CACHE_ITEMS: usize = 256;
+use crate::{
+    cache::DiskCache,
+    config::CachePolicy,
+    middleware::{
+        CacheItemInfo, CacheStats, Environment, FinishReason, GenerateRequest, ReloadRequest,
+        SessionError, Token, TokenCounter, TokenLogprob, TopLogprob,
+    },
+    sampler::grammar::TokenTrie,
+    state::StateTensors,
+};
 
 #[derive(Debug)]
 pub enum SlotResult {
@@ -45,16 +55,16 @@ pub enum SlotResult {
 #[derive(Debug)]
 enum SlotState {
     /// The slot might be either picked up or swapped.
-    Idle(Tokens, Instant),
+    Idle(Partition, Tokens, Instant),
     /// The slot is locked and is waiting for processing.
     Wait(Box<GenerateContext>),
-    /// The slot is currently under processing.
-    Busy,
+    /// The slot is currently under processing for the request of this ID, sent by this tenant.
+    Busy(String, Option<String>),
 }
 
 impl Default for SlotState {
     fn default() -> Self {
-        Self::Idle(Default::default(), Instant::now())
+        Self::Idle(Default::default(), Default::default(), Instant::now())
     }
 }
 
@@ -210,6 +220,8 @@ impl AsTokenSlice for [u16] {
 
 #[derive(Debug, Clone)]
 pub struct GenerateContext {
+    /// ID that the caller cancels the context by. Choices of the same request share it.
+    pub id: String,
     /// Tokens that are provided at first.
     pub prompt_tokens: Vec<u16>,
     /// Whether the prompt has already been processed and cached.
@@ -228,12 +240,55 @@ pub struct GenerateContext {
     pub request: GenerateRequest,
     /// To send back generated tokens.
     pub sender: Sender<Token>,
+    /// The context is finished with [`FinishReason::Timeout`] wherever it is after this.
+    pub deadline: Option<Instant>,
 }
 
+impl GenerateContext {
+    pub fn token_counter(&self) -> TokenCounter {
+        let prompt_tokens = self.prompt_tokens.len();
+        let completion_tokens = self.model_tokens.len();
+        let total_tokens = prompt_tokens + completion_tokens;
+        TokenCounter {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Tell the caller that the context ends early.
+    pub fn stop(&self, reason: FinishReason) {
+        let _ = self.sender.send(Token::Stop(reason, self.token_counter()));
+        let _ = self.sender.send(Token::Done);
+    }
+}
+
+/// Cached states are only shared among contexts of the same partition.
+/// For example, contexts that start from different initial states never share any prefix.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Partition {
+    /// Name of the initial state.
+    pub state: Option<String>,
+    /// Identity of the caller, if tenants are isolated.
+    pub tenant: Option<String>,
+}
+
+/// Name of an initial state, along with the tenant that owns it if any.
+pub type StateName = (String, Option<String>);
+
 #[derive(Debug)]
 struct CachedItem<B: BackedState> {
     backed: Arc<B>,
     instant: Instant,
+    /// Number of times that the state is checked out.
+    hits: usize,
+    /// Pinned items are never evicted.
+    pinned: bool,
 }
 
 impl<B: BackedState> CachedItem<B> {
@@ -241,6 +296,8 @@ impl<B: BackedState> CachedItem<B> {
         Self {
             backed: Arc::new(backed),
             instant: Instant::now(),
+            hits: 0,
+            pinned: false,
         }
     }
 
@@ -248,8 +305,18 @@ impl<B: BackedState> CachedItem<B> {
         Self {
             backed: item.backed,
             instant: Instant::now(),
+            hits: item.hits + 1,
+            pinned: item.pinned,
         }
     }
+
+    /// Put the item into `cache`. If it replaces a pinned item, it stays pinned.
+    pub fn insert_into(mut self, cache: &mut Trie<Tokens, CachedItem<B>>, tokens: Tokens) {
+        if let Some(item) = cache.get(&tokens) {
+            self.pinned |= item.pinned;
+        }
+        cache.insert(tokens, self);
+    }
 }
 
 impl<B: BackedState> Clone for CachedItem<B> {
@@ -257,14 +324,92 @@ impl<B: BackedState> Clone for CachedItem<B> {
         Self {
             backed: self.backed.clone(),
             instant: self.instant,
+            hits: self.hits,
+            pinned: self.pinned,
+        }
+    }
+}
+
+/// How well `checkout` finds cached states.
+#[derive(Debug, Default)]
+struct CheckoutCounter {
+    /// The whole input is cached.
+    hits: AtomicUsize,
+    /// Only part of the input is cached.
+    partial_hits: AtomicUsize,
+    /// Nothing of the input is cached.
+    misses: AtomicUsize,
+}
+
+impl CheckoutCounter {
+    fn record(&self, cached: usize, total: usize) {
+        let counter = match cached {
+            0 => &self.misses,
+            x if x >= total => &self.hits,
+            _ => &self.partial_hits,
+        };
+        counter.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+}
+
+/// Size of a backed state of one batch in bytes.
+fn state_size(info: &ModelInfo) -> usize {
+    let num_row = match info.version {
+        ModelVersion::V4 => 5,
+        ModelVersion::V5 | ModelVersion::V6 => info.num_emb / info.num_head + 2,
+    };
+    info.num_layer * info.num_emb * num_row * std::mem::size_of::<f32>()
+}
+
+/// A named conversation that keeps its own state, so that the client only sends new turns.
+#[derive(Debug)]
+struct Session<B: BackedState> {
+    /// All tokens of the conversation so far.
+    history: Vec<u16>,
+    /// The state after the leading tokens of the history, which has not seen the last sampled token yet.
+    backed: Option<(Vec<u16>, Arc<B>)>,
+    /// When the session was last used. Sessions idle for longer than the TTL are dropped.
+    instant: Instant,
+    /// The `sid` of the caller that created the session.
+    tenant: Option<String>,
+}
+
+impl<B: BackedState> Default for Session<B> {
+    fn default() -> Self {
+        Self {
+            history: vec![],
+            backed: None,
+            instant: Instant::now(),
+            tenant: None,
         }
     }
 }
 
+impl<B: BackedState> Clone for Session<B> {
+    fn clone(&self) -> Self {
+        Self {
+            history: self.history.clone(),
+            backed: self.backed.clone(),
+            instant: Instant::now(),
+            tenant: self.tenant.clone(),
+        }
+    }
+}
+
+// ids come from the OS random source, since knowing one lets a caller read or cancel what it names
+fn session_id() -> String {
+    format!("sess_{}", Uuid::new_v4().simple())
+}
+
+pub fn request_id() -> String {
+    format!("req_{}", Uuid::new_v4().simple())
+}
+
 pub trait Runner {
     fn info(&self) -> &ModelInfo;
     fn num_batch(&self) -> usize;
     fn tokenizer(&self) -> Arc<Tokenizer>;
+    fn penalty_free_tokens(&self) -> Arc<HashSet<u16>>;
 
     /// Serialize the model into the given path.
     fn serialize_model(&self, path: PathBuf) -> Result<()>;
@@ -281,8 +426,67 @@ pub trait Runner {
         payloads: &'a mut [Payload],
     ) -> Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
 
-    /// Keep the items in the cache less then [`MAX_CACHE_ITEMS`].
+    /// Keep the states in the cache within the memory budget.
     fn limit_cache(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+
+    /// Create an empty session and return its ID.
+    fn create_session(
+        &self,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<String, SessionError>> + Send + '_>>;
+
+    /// Copy a session into a new one and return the ID of the copy.
+    fn fork_session(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<String, SessionError>> + Send + '_>>;
+
+    /// Delete a session and return its token history.
+    fn delete_session(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Option<Vec<u16>>> + Send + '_>>;
+
+    /// List states in the memory cache.
+    fn cache_items(&self) -> Pin<Box<dyn Future<Output = Vec<CacheItemInfo>> + Send + '_>>;
+
+    /// Report how well the cache works.
+    fn cache_stats(&self) -> Pin<Box<dyn Future<Output = CacheStats> + Send + '_>>;
+
+    /// Remove cached states that start with `prefix`, both in memory and on disk, and return the number of them.
+    /// All states are removed if `prefix` is `None`.
+    fn flush_cache(
+        &self,
+        prefix: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<usize>> + Send + '_>>;
+
+    /// Names of initial states that requests may start from, each with the tenant that owns it if any.
+    fn states(&self) -> Pin<Box<dyn Future<Output = Vec<StateName>> + Send + '_>>;
+
+    /// Add an initial state from safetensors, or replace the one of the same name.
+    /// Under tenant isolation, the state is owned by `tenant` and only replaces or shadows states for that tenant.
+    fn import_state(
+        &self,
+        name: String,
+        data: Vec<u8>,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+
+    /// Token history of a session.
+    fn session(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Option<Vec<u16>>> + Send + '_>>;
+
+    /// Cancel the contexts of a request in slots, if the request is sent by `tenant`. Returns `false` if there is none.
+    fn cancel(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + '_>>;
 }
 
 #[derive(Debug)]
@@ -296,48 +500,126 @@ where
     model: M,
     state: S,
     tokenizer: Arc<Tokenizer>,
+    token_trie: Arc<TokenTrie>,
     slots: Mutex<Vec<SlotState>>,
-    backed: Mutex<Trie<Tokens, CachedItem<B>>>,
+    backed: Mutex<HashMap<Partition, Trie<Tokens, CachedItem<B>>>>,
+    /// Initial states keyed by their names and owners. States listed in the config are owned by no one.
+    states: RwLock<HashMap<StateName, Arc<B>>>,
+    sessions: Mutex<HashMap<String, Session<B>>>,
+    max_sessions: usize,
+    session_ttl: Duration,
     max_runtime_batch: usize,
     state_chunk_size: usize,
-    _penalty_free_tokens: HashSet<u16>,
+    penalty_free_tokens: Arc<HashSet<u16>>,
+    max_cache_items: usize,
+    cache_policy: CachePolicy,
+    prompt_cache_tokens: usize,
+    tenant_isolation: bool,
+    stats: CheckoutCounter,
+    disk: Option<Mutex<DiskCache>>,
+    /// Notified whenever slots become idle, so that queued requests are admitted at once.
+    idle: Arc<Notify>,
+    /// IDs of requests to cancel in the next round.
+    cancelled: Mutex<HashSet<String>>,
 }
 
 impl<M, S, B> Runtime<M, S, B>
 where
-    B: BackedState,
+    B: BackedState + StateTensors + Serialize + DeserializeOwned + Send + Sync + 'static,
     S: ModelState<BackedState = B>,
     M: Model<State = S> + Serialize,
     StateBuilder: Build<B, Error = Infallible>,
 {
     pub fn new(
         tokenizer: Tokenizer,
+        vocab: &[(u16, Vec<u8>)],
         model: M,
         state: S,
-        max_runtime_batch: usize,
-        state_chunk_size: usize,
-    ) -> Self {
+        reload: &ReloadRequest,
+        disk: Option<DiskCache>,
+        idle: Arc<Notify>,
+    ) -> Result<Self> {
+        let ReloadRequest {
+            max_runtime_batch,
+            state_chunk_size,
+            penalty_free_list,
+            state: init_states,
+            cache,
+            ..
+        } = reload;
+
         let slots = (0..state.num_batch())
             .map(|_| SlotState::default())
             .collect();
-        let _penalty_free_tokens = (0..u16::MAX)
-            .filter(|&token| {
-                let word = tokenizer.decode(&[token]).unwrap_or_default();
-                let word = String::from_utf8_lossy(&word).into_owned();
-                PENALTY_FREE_LIST.iter().any(|x| word.contains(x))
+        let penalty_free_tokens = vocab
+            .iter()
+            .filter(|(_, word)| {
+                let word = String::from_utf8_lossy(word);
+                penalty_free_list.iter().any(|x| word.contains(x))
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        let penalty_free_tokens = Arc::new(penalty_free_tokens);
+        let token_trie = Arc::new(TokenTrie::new(vocab.iter().cloned()));
+
+        let max_cache_items = (cache.memory_budget * 1024 * 1024 / state_size(model.info())).max(1);
+        log::info!("state cache holds at most {} items", max_cache_items);
+
+        let mut zero_state: B = StateBuilder::new(model.context(), model.info())
+            .with_chunk_size(*state_chunk_size)
+            .build()
+            .unwrap();
+        zero_state.fit(model.info());
+        let states = init_states
+            .iter()
+            .filter_map(|init| {
+                let load = || -> Result<_> {
+                    let data = std::fs::read(&init.path)?;
+                    let backed = crate::state::from_safetensors(&data, &zero_state)?;
+                    // make sure that the state fits the model before any request picks it up
+                    state.load_batch(&backed, 0)?;
+                    Ok(backed)
+                };
+                match load() {
+                    Ok(backed) => {
+                        log::info!("loaded initial state {} from {:?}", init.name, init.path);
+                        Some(((init.name.clone(), None), Arc::new(backed)))
+                    }
+                    Err(err) => {
+                        log::warn!(
+                            "skipped initial state {} from {:?}: {err}",
+                            init.name,
+                            init.path
+                        );
+                        None
+                    }
+                }
             })
             .collect();
 
-        Self {
+        Ok(Self {
             model,
             state,
             tokenizer: Arc::new(tokenizer),
+            token_trie,
             slots: Mutex::new(slots),
-            backed: Mutex::new(Trie::new()),
-            max_runtime_batch,
-            state_chunk_size,
-            _penalty_free_tokens,
-        }
+            backed: Default::default(),
+            states: RwLock::new(states),
+            sessions: Default::default(),
+            max_sessions: cache.max_sessions,
+            session_ttl: Duration::from_secs(cache.session_ttl),
+            max_runtime_batch: *max_runtime_batch,
+            state_chunk_size: *state_chunk_size,
+            penalty_free_tokens,
+            max_cache_items,
+            cache_policy: cache.policy,
+            prompt_cache_tokens: cache.prompt_cache_tokens,
+            tenant_isolation: cache.tenant_isolation,
+            stats: Default::default(),
+            disk: disk.map(Mutex::new),
+            idle,
+            cancelled: Default::default(),
+        })
     }
 
     fn serialize_model(&self, path: PathBuf) -> Result<()> {
@@ -359,29 +641,79 @@ where
         Ok(())
     }
 
-    /// Search for the longest common prefix in the memory cache and checkout the state from that point.
-    /// Should there be a cache miss, an initial state is returned.
-    async fn checkout(&self, tokens: &[u16], batch: usize) -> (Vec<u16>, Arc<B>) {
-        let mut cache = self.backed.lock().await;
+    fn zero_state(&self) -> B {
+        let context = self.model.context();
+        let info = self.model.info();
+        let mut state: B = StateBuilder::new(context, info)
+            .with_chunk_size(self.state_chunk_size)
+            .build()
+            .unwrap();
+        state.fit(info);
+        state
+    }
+
+    /// The cache partition that the request reads from and writes to.
+    fn partition(&self, request: &GenerateRequest) -> Partition {
+        let tenant = match self.tenant_isolation {
+            true => request.tenant.clone(),
+            false => None,
+        };
+        Partition {
+            state: request.state.clone(),
+            tenant,
+        }
+    }
+
+    /// Search for the longest common prefix in the memory cache of the request's partition and checkout the state from that point.
+    /// If the context continues a session, the state of the session is checked out instead.
+    /// Should there be a cache miss, the initial state selected by the request is returned.
+    async fn checkout(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        request: &GenerateRequest,
+    ) -> (Vec<u16>, Arc<B>) {
+        let partition = self.partition(request);
+        let mut caches = self.backed.lock().await;
+        let cache = caches.entry(partition.clone()).or_insert_with(Trie::new);
         let prefix = cache.longest_common_prefix(tokens.as_token_slice());
         let len = (1..=prefix.len())
             .rev()
             .find(|len| cache.contains_key(prefix[0..*len].as_token_slice()))
             .unwrap_or_default();
+
+        if let Some(id) = &request.session {
+            let sessions = self.sessions.lock().await;
+            if let Some((prefix, backed)) = sessions
+                .get(id)
+                .filter(|session| self.owns(session, &request.tenant))
+                .and_then(|x| x.backed.clone())
+            {
+                if prefix.len() > len && tokens.starts_with(&prefix) {
+                    log::info!(
+                        "slot {} checks out session {} of length {}",
+                        batch,
+                        id,
+                        prefix.len()
+                    );
+                    self.stats.record(prefix.len(), tokens.len());
+                    return (prefix, backed);
+                }
+            }
+        }
+
         log::info!("slot {} checks out backed cache of length {}", batch, len);
+        self.stats.record(len, tokens.len());
 
         let prefix = prefix[0..len].to_vec();
         let reload = match cache.remove(prefix[..].as_token_slice()) {
             Some(reload) => CachedItem::renew(reload),
-            None => {
-                let context = self.model.context();
-                let info = self.model.info();
-                let backed = StateBuilder::new(context, info)
-                    .with_chunk_size(self.state_chunk_size)
-                    .build()
-                    .unwrap();
-                CachedItem::new(backed)
-            }
+            None => CachedItem {
+                backed: self.initial_state(&partition).await,
+                instant: Instant::now(),
+                hits: 0,
+                pinned: false,
+            },
         };
         if len > 0 {
             let key = Tokens(prefix.clone());
@@ -390,15 +722,112 @@ where
         (prefix, reload.backed)
     }
 
+    /// The initial state selected by the partition, or a fresh state if there is none.
+    async fn initial_state(&self, partition: &Partition) -> Arc<B> {
+        let states = self.states.read().await;
+        let state = partition.state.as_ref().and_then(|name| {
+            states
+                .get(&(name.clone(), partition.tenant.clone()))
+                .or_else(|| states.get(&(name.clone(), None)))
+        });
+        match state {
+            Some(backed) => backed.clone(),
+            None => Arc::new(self.zero_state()),
+        }
+    }
+
+    /// Load the checked out state into the slot.
+    /// Should the state not fit the model, it is dropped from the cache and the slot starts over from the initial state.
+    /// Returns the prefix that the slot holds afterwards.
+    async fn load(
+        &self,
+        batch: usize,
+        prefix: Vec<u16>,
+        reload: Arc<B>,
+        request: &GenerateRequest,
+    ) -> Vec<u16> {
+        let Err(err) = self.state.load_batch(&reload, batch) else {
+            return prefix;
+        };
+        log::warn!(
+            "failed to load state of length {} into slot {}: {err}",
+            prefix.len(),
+            batch
+        );
+
+        let partition = self.partition(request);
+        if let Some(cache) = self.backed.lock().await.get_mut(&partition) {
+            cache.remove(prefix.as_token_slice());
+        }
+        let backed = self.initial_state(&partition).await;
+        if let Err(err) = self.state.load_batch(&backed, batch) {
+            log::error!("failed to load initial state into slot {}: {err}", batch);
+        }
+        vec![]
+    }
+
+    /// If the disk cache holds a longer prefix of `tokens` than the memory cache, load that state into the memory cache.
+    /// The file is read on the blocking pool without holding any lock, so that the runtime loop never waits on disk.
+    async fn prefetch(&self, tokens: &[u16], request: &GenerateRequest) {
+        // states on disk are keyed by tokens only, so only those of the default partition are there
+        let partition = self.partition(request);
+        let Some(disk) = self
+            .disk
+            .as_ref()
+            .filter(|_| partition == Partition::default())
+        else {
+            return;
+        };
+
+        let len = {
+            let caches = self.backed.lock().await;
+            caches.get(&partition).map_or(0, |cache| {
+                let prefix = cache.longest_common_prefix(tokens.as_token_slice());
+                (1..=prefix.len())
+                    .rev()
+                    .find(|len| cache.contains_key(prefix[0..*len].as_token_slice()))
+                    .unwrap_or_default()
+            })
+        };
+        let (prefix, path) = {
+            let mut disk = disk.lock().await;
+            let prefix = disk.longest_prefix(tokens);
+            if prefix.len() <= len {
+                return;
+            }
+            match disk.touch(&prefix) {
+                Some(path) => (prefix, path),
+                None => return,
+            }
+        };
+
+        let backed = tokio::task::spawn_blocking(move || DiskCache::read::<B>(&path)).await;
+        match backed.map_err(Into::into).and_then(|backed| backed) {
+            Ok(backed) => {
+                log::info!("loaded state of length {} from disk cache", prefix.len());
+                let mut caches = self.backed.lock().await;
+                let cache = caches.entry(partition).or_insert_with(Trie::new);
+                cache.insert(Tokens(prefix), CachedItem::new(backed));
+            }
+            Err(err) => {
+                log::warn!("failed to load state from disk: {err}");
+                let files = disk.lock().await.remove(&prefix);
+                tokio::task::spawn_blocking(move || DiskCache::delete(files));
+            }
+        }
+    }
+
     /// Queue an inference task.
     async fn queue(&self, context: GenerateContext) -> SlotResult {
-        let mut slots = self.slots.lock().await;
-
         // we must ensure that there is at least one token as the suffix, otherwise the whole slot will loop forever as there is no input
         let (last, tokens) = match [context.prefix, context.suffix].concat().split_last() {
             Some((last, tokens)) => (*last, tokens.to_vec()),
             None => return SlotResult::Error,
         };
+        self.prefetch(&tokens, &context.request).await;
+
+        let mut slots = self.slots.lock().await;
+        let partition = self.partition(&context.request);
 
         // find the best idle slot by:
         // 1. find the slot that matches the context (continue)
@@ -408,9 +837,10 @@ where
             .iter()
             .enumerate()
             .filter_map(|(batch, slot)| match slot {
-                SlotState::Idle(content, time) => {
+                SlotState::Idle(other, content, time) => {
                     let delta = time.elapsed().as_millis();
-                    match (content.is_empty(), tokens.starts_with(content)) {
+                    let matched = *other == partition && tokens.starts_with(content);
+                    match (content.is_empty(), matched) {
                         (true, _) => Some((SlotChoice::Empty(batch), delta)),
                         (false, true) => Some((SlotChoice::Continue(batch, content.len()), delta)),
                         (false, false) => Some((SlotChoice::Back(batch), delta)),
@@ -434,7 +864,8 @@ where
             // back a non-relative and non-empty slot and use it for our new context
             Some((SlotChoice::Back(batch), _)) => {
                 log::info!("start at non-empty slot {}", batch);
-                let (prefix, reload) = self.checkout(&tokens, batch).await;
+                let (prefix, reload) = self.checkout(&tokens, batch, &context.request).await;
+                let prefix = self.load(batch, prefix, reload, &context.request).await;
 
                 let tokens = [tokens, vec![last]].concat();
                 let len = prefix.len();
@@ -449,19 +880,15 @@ where
 
                 std::mem::swap(&mut state, &mut slots[batch]);
                 match state {
-                    SlotState::Idle(_, _) => {
-                        // let backed = self.state.back_batch(batch).await.unwarp();
-                        // cache.insert(content, backed.into());
-                        self.state.load_batch(&reload, batch).unwrap();
-                        SlotResult::Fault(batch)
-                    }
+                    SlotState::Idle(_, _, _) => SlotResult::Fault(batch),
                     _ => unreachable!(),
                 }
             }
             // directly occupy an empty slot so no need backing
             Some((SlotChoice::Empty(batch), _)) => {
                 log::info!("start at empty slot {}", batch);
-                let (prefix, reload) = self.checkout(&tokens, batch).await;
+                let (prefix, reload) = self.checkout(&tokens, batch, &context.request).await;
+                let prefix = self.load(batch, prefix, reload, &context.request).await;
 
                 let tokens = [tokens, vec![last]].concat();
                 let len = prefix.len();
@@ -474,8 +901,6 @@ where
                     .into(),
                 );
                 slots[batch] = state;
-
-                self.state.load_batch(&reload, batch).expect("load state");
                 SlotResult::Fault(batch)
             }
             // continue from an existing slot. No need backing as well
@@ -499,21 +924,23 @@ where
     /// This critical section synchronizes `slots` and fills `payloads`.
     async fn prepare(&self, payloads: &mut [Payload]) {
         let mut slots = self.slots.lock().await;
-        let mut cache = self.backed.lock().await;
+        let mut caches = self.backed.lock().await;
 
         // sync payloads and slots: kill dead payloads
         for (slot, payload) in slots.iter().zip_eq(payloads.iter_mut()) {
-            if !(payload.is_empty() || matches!(slot, SlotState::Busy)) {
+            if !(payload.is_empty() || matches!(slot, SlotState::Busy(..))) {
                 log::warn!("payload should either be empty or slot should be busy");
                 *payload = Payload::Empty;
             }
         }
 
         // reset all finished slots to idle
+        let mut freed = 0;
         for (batch, payload) in payloads.iter_mut().enumerate() {
             let Some(context) = payload.take() else {
                 continue;
             };
+            freed += 1;
 
             let backed = self.state.back_batch(batch).await.unwrap();
 
@@ -526,31 +953,80 @@ where
                 let _ = context.sender.send(Token::Embed(embed));
             }
 
-            cache.insert(context.prefix.clone(), CachedItem::new(backed));
+            let partition = self.partition(&context.request);
+            let item = CachedItem {
+                pinned: context.request.pin,
+                ..CachedItem::new(backed)
+            };
+
+            if context.request.export {
+                // serializing a state takes a while, so it is done off the process thread
+                let backed = item.backed.clone();
+                let sender = context.sender.clone();
+                tokio::task::spawn_blocking(move || {
+                    match crate::state::to_safetensors(backed.as_ref()) {
+                        Ok(data) => {
+                            let _ = sender.send(Token::State(data));
+                        }
+                        Err(err) => log::error!("failed to export state: {err}"),
+                    }
+                });
+            }
+
+            let cache = caches.entry(partition.clone()).or_insert_with(Trie::new);
+            item.clone().insert_into(cache, context.prefix.clone());
             log::info!("backed slot {} of length {}", batch, context.prefix.len());
 
-            assert!(matches!(slots[batch], SlotState::Busy));
-            slots[batch] = SlotState::Idle(context.prefix, Instant::now());
+            if let Some(id) = &context.request.session {
+                let mut sessions = self.sessions.lock().await;
+                let session = sessions
+                    .get_mut(id)
+                    .filter(|session| self.owns(session, &context.request.tenant));
+                if let Some(session) = session {
+                    session.history = [&context.prefix.0[..], &context.suffix.0[..]].concat();
+                    session.backed = Some((context.prefix.to_vec(), item.backed));
+                    session.instant = Instant::now();
+                }
+            }
+
+            assert!(matches!(slots[batch], SlotState::Busy(..)));
+            slots[batch] = SlotState::Idle(partition, context.prefix, Instant::now());
+        }
+        if freed > 0 {
+            self.idle.notify_one();
         }
 
-        // take data from some waiting slots
+        // take data from some waiting slots, interactive ones first
         let occupancy = payloads
             .iter()
             .filter(|x| matches!(x, Payload::Busy(_)))
             .count();
         let remain = self.max_runtime_batch - self.max_runtime_batch.min(occupancy);
-        let batches = slots
+        // cancelled and expired ones are taken regardless, since they finish right away without running the model
+        let cancelled = self.cancelled.lock().await;
+        let (expired, waiting): (Vec<_>, Vec<_>) = slots
             .iter()
             .enumerate()
-            .filter(|(_, slot)| matches!(slot, SlotState::Wait(_)))
+            .filter_map(|(batch, slot)| match slot {
+                SlotState::Wait(context) => Some((batch, context)),
+                _ => None,
+            })
+            .partition(|(_, context)| cancelled.contains(&context.id) || context.is_expired());
+        drop(cancelled);
+        let batches = waiting
+            .into_iter()
+            .sorted_by_key(|(_, context)| context.request.priority.unwrap_or_default())
             .take(remain)
+            .chain(expired)
             .map(|(batch, _)| batch)
             .collect_vec();
         for batch in batches {
-            let mut slot = SlotState::Busy;
+            let mut slot = SlotState::Busy(Default::default(), None);
             std::mem::swap(&mut slots[batch], &mut slot);
             match slot {
                 SlotState::Wait(context) => {
+                    let tenant = context.request.tenant.clone();
+                    slots[batch] = SlotState::Busy(context.id.clone(), tenant);
                     let _ = context.sender.send(Token::Start);
                     assert!(matches!(payloads[batch], Payload::Empty));
                     payloads[batch] = Payload::Busy(*context);
@@ -558,6 +1034,23 @@ where
                 _ => unreachable!(),
             };
         }
+
+        // finish contexts that are cancelled or run out of time, whether they are still reading the prompt or generating
+        let mut cancelled = self.cancelled.lock().await;
+        for payload in payloads.iter_mut() {
+            let Payload::Busy(context) = payload else {
+                continue;
+            };
+            let reason = match (cancelled.contains(&context.id), context.is_expired()) {
+                (true, _) => FinishReason::Cancelled,
+                (false, true) => FinishReason::Timeout,
+                (false, false) => continue,
+            };
+            context.stop(reason);
+            payload.finalize();
+        }
+        // all cancelled contexts in slots are taken above, so none of them is left behind
+        cancelled.clear();
     }
 
     async fn process(&self, payloads: &mut [Payload]) -> Result<()> {
@@ -575,13 +1068,19 @@ where
             })
             .collect_vec();
 
-        // run the model until there is at least one slot finished
+        // run the model until there is at least one slot finished, or one context expires in a long prompt
         let occupancy = payloads.iter().filter(|x| x.is_busy()).count();
+        let expired = || {
+            payloads.iter().any(|payload| match payload {
+                Payload::Busy(context) => context.is_expired(),
+                _ => false,
+            })
+        };
         let outputs = match occupancy {
             0 => vec![ModelOutput::None; payloads.len()],
             _ => loop {
                 let output = self.model.run(&mut inputs, &self.state).await?;
-                if output.iter().any(ModelOutput::is_some) {
+                if output.iter().any(ModelOutput::is_some) || expired() {
                     break output;
                 }
             },
@@ -590,13 +1089,16 @@ where
         // update raw outputs
         let handles = payloads
             .iter()
-            .zip_eq(outputs.into_iter())
+            .zip_eq(outputs)
             .map(|(payload, output)| match payload {
-                Payload::Busy(context) => match output {
+                Payload::Busy(context) if !context.request.prefill => match output {
                     ModelOutput::None => None,
                     ModelOutput::Last(data) => Some((
                         context.request.sampler.clone(),
                         context.request.bias.clone(),
+                        context.request.grammar.clone(),
+                        self.token_trie.clone(),
+                        context.request.logprobs.is_some(),
                         data,
                     )),
                     ModelOutput::Full(_) => unreachable!(),
@@ -605,28 +1107,33 @@ where
             })
             .map(|bundle| async move {
                 match bundle {
-                    Some((sampler, bias, mut data)) => {
-                        sampler.read().await.transform(&mut data);
-                        for (token, bias) in bias.iter() {
-                            data[*token as usize] += *bias
+                    Some((sampler, bias, grammar, trie, logprobs, mut data)) => {
+                        // log probabilities are reported from the model itself, before any sampling stage
+                        let probs = logprobs.then(|| crate::sampler::softmax(&data));
+                        // the grammar goes first, so that truncation stages only pick from valid tokens
+                        if let Some(grammar) = grammar {
+                            grammar.read().await.mask(&trie, &mut data);
                         }
-                        Some(data)
+                        sampler.read().await.transform(&mut data, &bias);
+                        Some((data, probs))
                     }
                     None => None,
                 }
             })
             .map(tokio::spawn)
             .collect_vec();
-        let outputs = {
+        let (outputs, model_probs) = {
             let mut outputs = vec![];
+            let mut model_probs = vec![];
             for handle in handles {
-                let output = match handle.await? {
-                    Some(data) => ModelOutput::Last(data),
-                    None => ModelOutput::None,
+                let (output, probs) = match handle.await? {
+                    Some((data, probs)) => (ModelOutput::Last(data), probs),
+                    None => (ModelOutput::None, None),
                 };
                 outputs.push(output);
+                model_probs.push(probs);
             }
-            outputs
+            (outputs, model_probs)
         };
 
         // compute probabilities
@@ -638,9 +1145,9 @@ where
         // sample tokens
         let handles = payloads
             .iter()
-            .zip_eq(outputs.into_iter())
+            .zip_eq(outputs)
             .map(|(payload, output)| match payload {
-                Payload::Busy(context) => match output {
+                Payload::Busy(context) if !context.request.prefill => match output {
                     ModelOutput::None => None,
                     ModelOutput::Last(data) => Some((context.request.sampler.clone(), data)),
                     ModelOutput::Full(_) => unreachable!(),
@@ -649,7 +1156,10 @@ where
             })
             .map(|bundle| async move {
                 match bundle {
-                    Some((sampler, data)) => Some(sampler.write().await.sample(&data)),
+                    Some((sampler, data)) => {
+                        let token = sampler.write().await.sample(&data);
+                        Some((token, data))
+                    }
                     None => None,
                 }
             })
@@ -663,11 +1173,11 @@ where
             outputs
         };
 
-        for (batch, payload, token, input) in payloads
+        for (batch, payload, token, input, model_probs) in payloads
             .iter_mut()
-            .zip_eq(outputs.into_iter().zip_eq(inputs.into_iter()))
+            .zip_eq(outputs.into_iter().zip_eq(inputs).zip_eq(model_probs))
             .enumerate()
-            .map(|(i, (x, (y, z)))| (i, x, y, z))
+            .map(|(i, (x, ((y, z), w)))| (i, x, y, z, w))
         {
             let Payload::Busy(context) = payload else {
                 continue;
@@ -683,16 +1193,28 @@ where
             context.prefix = Tokens(model_tokens[..len].to_vec());
             context.suffix = Tokens(model_tokens[len..].to_vec());
 
-            let Some(token) = token else {
+            // a prefill finishes as soon as the whole prompt is read, and its state is backed without sampling
+            if context.request.prefill {
+                if context.suffix.is_empty() {
+                    context.stop(FinishReason::Stop);
+                    payload.finalize();
+                }
+                continue;
+            }
+
+            let Some((token, probs)) = token else {
                 continue;
             };
 
             // cache the prompt if it is too long.
-            if !context.prompt_cached && context.prompt_tokens.len() > PROMPT_CACHE_TOKENS {
-                let mut cache = self.backed.lock().await;
+            if !context.prompt_cached && context.prompt_tokens.len() > self.prompt_cache_tokens {
+                let mut caches = self.backed.lock().await;
                 let backed = self.state.back_batch(batch).await.unwrap();
 
-                cache.insert(context.prefix.clone(), CachedItem::new(backed));
+                let cache = caches
+                    .entry(self.partition(&context.request))
+                    .or_insert_with(Trie::new);
+                CachedItem::new(backed).insert_into(cache, context.prefix.clone());
                 context.prompt_cached = true;
 
                 log::info!(
@@ -705,33 +1227,40 @@ where
             assert_eq!(context.suffix.len(), 0);
             context.suffix.0.push(token);
 
+            if let Some(top) = context.request.logprobs {
+                let probs = model_probs.as_deref().unwrap_or(&probs);
+                let logprob = self.logprob(token, probs, top)?;
+                let _ = context.sender.send(Token::Logprob(logprob));
+            }
+
             let mut word = self.tokenizer.decode(&[token])?;
+            let grammar_complete = match &context.request.grammar {
+                Some(grammar) => {
+                    let mut grammar = grammar.write().await;
+                    grammar.feed_bytes(&word);
+                    grammar.is_complete()
+                }
+                None => false,
+            };
             context.model_text.append(&mut word.clone());
             context.buffer.append(&mut word);
             context.model_tokens.push(token);
 
-            let count_tokens = || {
-                let prompt_tokens = context.prompt_tokens.len();
-                let completion_tokens = context.model_tokens.len();
-                let total_tokens = prompt_tokens + completion_tokens;
-                TokenCounter {
-                    prompt_tokens,
-                    completion_tokens,
-                    total_tokens,
-                }
-            };
-
             let mut done = false;
             let mut finish = |reason| {
-                let _ = context.sender.send(Token::Stop(reason, count_tokens()));
+                let counter = context.token_counter();
+                let _ = context.sender.send(Token::Stop(reason, counter));
                 let _ = context.sender.send(Token::Done);
                 done = true;
             };
 
             // here we detect if there is a stop word in our buffer
-            let ((head, tail), stop_matched) = context
-                .request
-                .stop
+            // with a grammar, the output only ends when the value is complete, lest a stop word cut it in the middle
+            let stop = match context.request.grammar {
+                Some(_) => &[][..],
+                None => &context.request.stop[..],
+            };
+            let ((head, tail), stop_matched) = stop
                 .iter()
                 .map(|stop| {
                     let stop = stop.as_bytes();
@@ -769,6 +1298,10 @@ where
                 let output = String::from_utf8_lossy(head);
                 let _ = context.sender.send(Token::Content(output.into()));
                 finish(FinishReason::Stop);
+            } else if grammar_complete {
+                let output = String::from_utf8_lossy(&context.buffer);
+                let _ = context.sender.send(Token::Content(output.into()));
+                finish(FinishReason::Stop);
             } else if context.model_tokens.len() >= context.request.max_tokens {
                 finish(FinishReason::Length);
             } else if let Ok(word) = String::from_utf8(head.to_vec()) {
@@ -782,31 +1315,341 @@ where
         Ok(())
     }
 
-    /// Keep the items in the cache less then [`MAX_CACHE_ITEMS`].
+    /// Collect the log probability of the chosen token, along with the `top` most likely tokens.
+    fn logprob(&self, token: u16, probs: &[f32], top: usize) -> Result<TokenLogprob> {
+        let top_logprob = |token: u16| -> Result<TopLogprob> {
+            let bytes = self.tokenizer.decode(&[token])?;
+            Ok(TopLogprob {
+                token: String::from_utf8_lossy(&bytes).into(),
+                logprob: probs[token as usize].max(f32::MIN_POSITIVE).ln(),
+                bytes,
+            })
+        };
+
+        let TopLogprob {
+            token,
+            logprob,
+            bytes,
+        } = top_logprob(token)?;
+        let top_logprobs = probs
+            .iter()
+            .enumerate()
+            .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
+            .take(top)
+            .map(|(token, _)| top_logprob(token as u16))
+            .try_collect()?;
+
+        Ok(TokenLogprob {
+            token,
+            logprob,
+            bytes,
+            top_logprobs,
+        })
+    }
+
+    /// Keep the states in the cache within the memory budget, evicting those that the policy values the least.
+    /// Idle sessions are expired here as well.
+    /// Pinned states are never evicted, even if they alone exceed the budget.
     async fn limit_cache(&self) {
-        let mut cache = self.backed.lock().await;
-        if cache.count() <= MAX_CACHE_ITEMS {
+        // states held by sessions take their share of the budget
+        let max_cache_items = self
+            .max_cache_items
+            .saturating_sub(self.expire_sessions().await);
+
+        let mut caches = self.backed.lock().await;
+        let count: usize = caches.values().map(|cache| cache.count()).sum();
+        if count <= max_cache_items {
             return;
         }
 
-        let mut removing = vec![];
-        for (tokens, _) in cache
+        // number of cached states that extend each state, which only matters to the prefix-aware policy
+        let policy = self.cache_policy;
+        let descendants = |cache: &Trie<Tokens, CachedItem<B>>, tokens: &Tokens| match policy {
+            CachePolicy::Prefix => cache.iter_prefix(tokens).count() - 1,
+            _ => 0,
+        };
+        let removing = caches
             .iter()
-            .sorted_unstable_by_key(|(_, item)| item.instant.elapsed())
-            .skip(MAX_CACHE_ITEMS)
-        {
-            removing.push(tokens.to_owned());
+            .flat_map(|(partition, cache)| {
+                cache.iter().map(move |(tokens, item)| {
+                    let descendants = descendants(cache, tokens);
+                    (partition, tokens, item, descendants)
+                })
+            })
+            .sorted_unstable_by(|(_, _, x, x_descendants), (_, _, y, y_descendants)| {
+                // items in the front are kept
+                let recency = x.instant.cmp(&y.instant).reverse();
+                let order = match policy {
+                    CachePolicy::Lru => recency,
+                    CachePolicy::Lfu => x.hits.cmp(&y.hits).reverse().then(recency),
+                    CachePolicy::Prefix => x_descendants.cmp(y_descendants).reverse().then(recency),
+                };
+                x.pinned.cmp(&y.pinned).reverse().then(order)
+            })
+            .skip(max_cache_items)
+            .filter(|(_, _, item, _)| !item.pinned)
+            .map(|(partition, tokens, _, _)| (partition.to_owned(), tokens.to_owned()))
+            .collect_vec();
+
+        let removed = removing
+            .into_iter()
+            .filter_map(|(partition, tokens)| {
+                let item = caches.get_mut(&partition)?.remove(&tokens)?;
+                Some((partition, tokens, item))
+            })
+            .collect_vec();
+        drop(caches);
+
+        // spill evicted states to disk instead of dropping them
+        if let Some(disk) = &self.disk {
+            let spilling = removed
+                .into_iter()
+                .filter(|(partition, tokens, _)| {
+                    !tokens.is_empty() && *partition == Partition::default()
+                })
+                .map(|(_, tokens, item)| (tokens.0, item.backed))
+                .collect_vec();
+            if spilling.is_empty() {
+                return;
+            }
+
+            let dir = disk.lock().await.dir().to_path_buf();
+            let written = tokio::task::spawn_blocking(move || {
+                spilling
+                    .into_iter()
+                    .filter_map(|(tokens, backed)| {
+                        match DiskCache::write(&dir, &tokens, backed.as_ref()) {
+                            Ok(item) => Some((tokens, item)),
+                            Err(err) => {
+                                log::warn!("failed to store state to disk: {err}");
+                                None
+                            }
+                        }
+                    })
+                    .collect_vec()
+            })
+            .await
+            .unwrap_or_default();
+
+            let files = {
+                let mut disk = disk.lock().await;
+                written
+                    .into_iter()
+                    .flat_map(|(tokens, item)| disk.insert(tokens, item))
+                    .collect_vec()
+            };
+            tokio::task::spawn_blocking(move || DiskCache::delete(files));
+        }
+    }
+
+    async fn cache_items(&self) -> Vec<CacheItemInfo> {
+        const PREVIEW_TOKENS: usize = 32;
+
+        let caches = self.backed.lock().await;
+        let now = SystemTime::now();
+        caches
+            .iter()
+            .flat_map(|(partition, cache)| {
+                cache
+                    .iter()
+                    .map(move |(tokens, item)| (partition, tokens, item))
+            })
+            .sorted_unstable_by_key(|(_, _, item)| item.instant.elapsed())
+            .map(|(partition, tokens, item)| {
+                let preview = &tokens.0[..tokens.len().min(PREVIEW_TOKENS)];
+                let preview = self.tokenizer.decode(preview).unwrap_or_default();
+                let last_access = now
+                    .checked_sub(item.instant.elapsed())
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                CacheItemInfo {
+                    state: partition.state.clone(),
+                    tenant: partition.tenant.clone(),
+                    len: tokens.len(),
+                    preview: String::from_utf8_lossy(&preview).into(),
+                    last_access: last_access.as_secs(),
+                    hits: item.hits,
+                    pinned: item.pinned,
+                }
+            })
+            .collect()
+    }
+
+    async fn cache_stats(&self) -> CacheStats {
+        let caches = self.backed.lock().await;
+        CacheStats {
+            hits: self.stats.hits.load(AtomicOrdering::Relaxed),
+            partial_hits: self.stats.partial_hits.load(AtomicOrdering::Relaxed),
+            misses: self.stats.misses.load(AtomicOrdering::Relaxed),
+            items: caches.values().map(|cache| cache.count()).sum(),
+            max_items: self.max_cache_items,
+        }
+    }
+
+    async fn flush_cache(&self, prefix: Option<String>) -> Result<usize> {
+        let prefix = match prefix {
+            Some(prefix) => self.tokenizer.encode(prefix.as_bytes())?,
+            None => vec![],
+        };
+
+        let mut caches = self.backed.lock().await;
+        let mut count = 0;
+        for cache in caches.values_mut() {
+            let removing = cache
+                .iter_prefix(prefix.as_token_slice())
+                .map(|(tokens, _)| tokens.to_owned())
+                .collect_vec();
+            for tokens in removing {
+                cache.remove(&tokens);
+                count += 1;
+            }
+        }
+        drop(caches);
+
+        if let Some(disk) = &self.disk {
+            let files = disk.lock().await.flush(&prefix);
+            count += files.len();
+            tokio::task::spawn_blocking(move || DiskCache::delete(files));
+        }
+
+        log::info!("flushed {} cached states", count);
+        Ok(count)
+    }
+
+    async fn states(&self) -> Vec<StateName> {
+        let states = self.states.read().await;
+        states.keys().cloned().sorted().collect()
+    }
+
+    async fn import_state(
+        &self,
+        name: String,
+        data: Vec<u8>,
+        tenant: Option<String>,
+    ) -> Result<()> {
+        let backed = crate::state::from_safetensors(&data, &self.zero_state())?;
+        let owner = match self.tenant_isolation {
+            true => tenant,
+            false => None,
+        };
+
+        let mut slots = self.slots.lock().await;
+        let mut caches = self.backed.lock().await;
+        let mut states = self.states.write().await;
+
+        // everything computed from the replaced state is now stale, whoever computed it, unless the state only replaces one for its owner
+        let state = Some(name.clone());
+        let stale = |partition: &Partition| {
+            partition.state == state && (owner.is_none() || partition.tenant == owner)
+        };
+        caches.retain(|partition, _| !stale(partition));
+        for slot in slots.iter_mut() {
+            if matches!(slot, SlotState::Idle(other, _, _) if stale(other)) {
+                *slot = SlotState::default();
+            }
         }
 
-        for tokens in removing.into_iter() {
-            cache.remove(&tokens);
+        states.insert((name.clone(), owner), Arc::new(backed));
+        log::info!("imported initial state {}", name);
+        Ok(())
+    }
+
+    /// Whether the caller may use the session. Sessions are private to their tenants under tenant isolation.
+    fn owns(&self, session: &Session<B>, tenant: &Option<String>) -> bool {
+        !self.tenant_isolation || session.tenant == *tenant
+    }
+
+    async fn create_session(&self, tenant: Option<String>) -> Result<String, SessionError> {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.len() >= self.max_sessions {
+            return Err(SessionError::Full);
+        }
+        let id = session_id();
+        let session = Session {
+            tenant,
+            ..Default::default()
+        };
+        sessions.insert(id.clone(), session);
+        log::info!("created session {}", id);
+        Ok(id)
+    }
+
+    async fn fork_session(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Result<String, SessionError> {
+        let mut sessions = self.sessions.lock().await;
+        let mut session = sessions
+            .get(&id)
+            .filter(|session| self.owns(session, &tenant))
+            .ok_or(SessionError::NotFound)?
+            .clone();
+        session.tenant = tenant;
+        if sessions.len() >= self.max_sessions {
+            return Err(SessionError::Full);
         }
+        let fork = session_id();
+        sessions.insert(fork.clone(), session);
+        log::info!("forked session {} into {}", id, fork);
+        Ok(fork)
+    }
+
+    async fn delete_session(&self, id: String, tenant: Option<String>) -> Option<Vec<u16>> {
+        let mut sessions = self.sessions.lock().await;
+        if !self.owns(sessions.get(&id)?, &tenant) {
+            return None;
+        }
+        let session = sessions.remove(&id)?;
+        log::info!("deleted session {}", id);
+        Some(session.history)
+    }
+
+    async fn session(&self, id: String, tenant: Option<String>) -> Option<Vec<u16>> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(&id)?;
+        if !self.owns(session, &tenant) {
+            return None;
+        }
+        session.instant = Instant::now();
+        Some(session.history.clone())
+    }
+
+    /// Drop sessions that have been idle for longer than the TTL, and return the number of states the rest hold.
+    async fn expire_sessions(&self) -> usize {
+        let mut sessions = self.sessions.lock().await;
+        sessions.retain(|id, session| {
+            let alive = session.instant.elapsed() < self.session_ttl;
+            if !alive {
+                log::info!("session {} expired", id);
+            }
+            alive
+        });
+        sessions
+            .values()
+            .filter(|session| session.backed.is_some())
+            .count()
+    }
+
+    async fn cancel(&self, id: String, tenant: Option<String>) -> bool {
+        // the lock on slots keeps `prepare` from missing a context that is waiting in a slot
+        let slots = self.slots.lock().await;
+        let found = slots.iter().any(|slot| match slot {
+            SlotState::Wait(context) => context.id == id && context.request.tenant == tenant,
+            SlotState::Busy(other, owner) => *other == id && *owner == tenant,
+            SlotState::Idle(..) => false,
+        });
+        if found {
+            log::info!("cancelled request {}", id);
+            self.cancelled.lock().await.insert(id);
+        }
+        found
     }
 }
 
 impl<M, S, B> Runner for Runtime<M, S, B>
 where
-    B: BackedState + Send + Sync,
+    B: BackedState + StateTensors + Serialize + DeserializeOwned + Send + Sync + 'static,
     S: ModelState<BackedState = B> + Send + Sync,
     M: Model<State = S> + Serialize + Send + Sync,
     StateBuilder: Build<B, Error = Infallible>,
@@ -826,6 +1669,11 @@ where
         self.tokenizer.clone()
     }
 
+    #[inline]
+    fn penalty_free_tokens(&self) -> Arc<HashSet<u16>> {
+        self.penalty_free_tokens.clone()
+    }
+
     #[inline]
     fn serialize_model(&self, path: PathBuf) -> Result<()> {
         Runtime::serialize_model(self, path)
@@ -851,10 +1699,94 @@ where
     fn limit_cache(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
         Box::pin(self.limit_cache())
     }
+
+    #[inline]
+    fn create_session(
+        &self,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<String, SessionError>> + Send + '_>> {
+        Box::pin(self.create_session(tenant))
+    }
+
+    #[inline]
+    fn fork_session(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<String, SessionError>> + Send + '_>> {
+        Box::pin(self.fork_session(id, tenant))
+    }
+
+    #[inline]
+    fn delete_session(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Option<Vec<u16>>> + Send + '_>> {
+        Box::pin(self.delete_session(id, tenant))
+    }
+
+    #[inline]
+    fn cache_items(&self) -> Pin<Box<dyn Future<Output = Vec<CacheItemInfo>> + Send + '_>> {
+        Box::pin(self.cache_items())
+    }
+
+    #[inline]
+    fn cache_stats(&self) -> Pin<Box<dyn Future<Output = CacheStats> + Send + '_>> {
+        Box::pin(self.cache_stats())
+    }
+
+    #[inline]
+    fn flush_cache(
+        &self,
+        prefix: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<usize>> + Send + '_>> {
+        Box::pin(self.flush_cache(prefix))
+    }
+
+    #[inline]
+    fn states(&self) -> Pin<Box<dyn Future<Output = Vec<StateName>> + Send + '_>> {
+        Box::pin(self.states())
+    }
+
+    #[inline]
+    fn import_state(
+        &self,
+        name: String,
+        data: Vec<u8>,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(self.import_state(name, data, tenant))
+    }
+
+    #[inline]
+    fn session(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Option<Vec<u16>>> + Send + '_>> {
+        Box::pin(self.session(id, tenant))
+    }
+
+    #[inline]
+    fn cancel(
+        &self,
+        id: String,
+        tenant: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        Box::pin(self.cancel(id, tenant))
+    }
 }
 
+/// A runtime that is running, along with its payloads.
+type Task = (Arc<dyn Runner + Send + Sync>, Vec<Payload>);
+
 #[tokio::main]
-pub async fn run(receiver: Receiver<()>, env: Arc<RwLock<Environment>>) {
+pub async fn run(
+    receiver: Receiver<()>,
+    retired: Receiver<Arc<dyn Runner + Send + Sync>>,
+    env: Arc<RwLock<Environment>>,
+) {
     {
         // this task constantly runs, cleaning up state cache
         let env = env.clone();
@@ -868,18 +1800,38 @@ pub async fn run(receiver: Receiver<()>, env: Arc<RwLock<Environment>>) {
         });
     }
 
+    // runtimes with requests in flight, each with its own payloads
+    // those swapped out by a reload keep running here until their requests finish
+    let mut tasks: Vec<Task> = vec![];
+    let track = |tasks: &mut Vec<Task>, runtime: &Arc<dyn Runner + Send + Sync>| {
+        if !tasks.iter().any(|(x, _)| Arc::ptr_eq(x, runtime)) {
+            let payloads = vec![Payload::default(); runtime.num_batch()];
+            tasks.push((runtime.clone(), payloads));
+        }
+    };
+
     while let Ok(()) = receiver.recv_async().await {
-        if let Environment::Loaded { runtime, .. } = &*env.read().await {
-            let mut payloads = vec![Payload::default(); runtime.num_batch()];
-            'run: loop {
-                if let Err(err) = runtime.process(&mut payloads).await {
+        'run: loop {
+            // the lock is not held while running, so that a reload can swap the runtime at any time
+            for runtime in retired.try_iter() {
+                track(&mut tasks, &runtime);
+            }
+            if let Environment::Loaded { runtime, .. } = &*env.read().await {
+                track(&mut tasks, runtime);
+            }
+
+            for (runtime, payloads) in tasks.iter_mut() {
+                if let Err(err) = runtime.process(payloads).await {
                     log::error!("{}", err);
-                    break 'run;
-                }
-                if payloads.iter().all(Payload::is_empty) {
-                    break 'run;
+                    payloads.clear();
                 }
             }
+
+            // a retired runtime is dropped here once its last request is done
+            tasks.retain(|(_, payloads)| !payloads.iter().all(Payload::is_empty));
+            if tasks.is_empty() {
+                break 'run;
+            }
         }
     }
 }
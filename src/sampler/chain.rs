@@ -0,0 +1,196 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use derivative::Derivative;
+use itertools::Itertools;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    min_p, nucleus,
+    penalty::{PenaltyParams, PenaltyState},
+    tail_free, top_k, typical, Sampler,
+};
+
+/// A stage of the sampler chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Subtract presence and frequency penalties.
+    #[serde(alias = "penalties")]
+    Penalty,
+    /// Add logit bias of the request.
+    #[serde(alias = "logit_bias")]
+    Bias,
+    /// Divide logits by `temperature`.
+    Temperature,
+    /// Keep the `top_k` most likely tokens.
+    TopK,
+    /// Keep the most likely tokens within cumulative probability `top_p`.
+    TopP,
+    /// Keep tokens with probabilities no less than `min_p` times that of the most likely token.
+    MinP,
+    /// Keep the most typical tokens within cumulative probability `typical_p`.
+    #[serde(alias = "typical_p")]
+    Typical,
+    /// Cut off the flat tail of the distribution, with `tfs_z`.
+    #[serde(alias = "tfs_z")]
+    TailFree,
+}
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+pub struct ChainParams {
+    /// Stages that run in order. The token is drawn from the distribution left by the last stage.
+    #[derivative(Default(value = "default_samplers()"))]
+    pub samplers: Vec<Stage>,
+    #[derivative(Default(value = "1.0"))]
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    /// The top-k stage is skipped if this is 0.
+    #[serde(default)]
+    pub top_k: usize,
+    #[derivative(Default(value = "1.0"))]
+    #[serde(default = "default_top_p")]
+    pub top_p: f32,
+    #[serde(default)]
+    pub min_p: f32,
+    #[derivative(Default(value = "1.0"))]
+    #[serde(default = "default_typical_p")]
+    pub typical_p: f32,
+    #[derivative(Default(value = "1.0"))]
+    #[serde(default = "default_tfs_z")]
+    pub tfs_z: f32,
+    #[serde(flatten)]
+    pub penalty: PenaltyParams,
+    /// Seed of the random number generator. Results are reproducible if this is set.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+fn default_samplers() -> Vec<Stage> {
+    vec![
+        Stage::Penalty,
+        Stage::Bias,
+        Stage::Temperature,
+        Stage::TopK,
+        Stage::TopP,
+        Stage::MinP,
+    ]
+}
+
+fn default_temperature() -> f32 {
+    ChainParams::default().temperature
+}
+
+fn default_top_p() -> f32 {
+    ChainParams::default().top_p
+}
+
+fn default_typical_p() -> f32 {
+    ChainParams::default().typical_p
+}
+
+fn default_tfs_z() -> f32 {
+    ChainParams::default().tfs_z
+}
+
+/// Mask out tokens that are not kept by a truncation stage.
+fn truncate(output: &mut [f32], candidates: impl FnOnce(&[f32]) -> Vec<(usize, f32)>) {
+    let probs = super::softmax(output);
+    let keep: HashSet<usize> = candidates(&probs)
+        .into_iter()
+        .filter(|(_, x)| *x > 0.0)
+        .map(|(id, _)| id)
+        .collect();
+    if keep.is_empty() {
+        return;
+    }
+    for (id, x) in output.iter_mut().enumerate() {
+        if !keep.contains(&id) {
+            *x = f32::NEG_INFINITY;
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ChainState {
+    pub penalty: PenaltyState,
+    pub rng: fastrand::Rng,
+}
+
+/// A sampler composed of configurable stages, which process the raw model output in order.
+#[derive(Debug, Default, Clone)]
+pub struct ChainSampler {
+    pub params: ChainParams,
+    pub state: ChainState,
+}
+
+impl ChainSampler {
+    pub fn new(params: ChainParams) -> Self {
+        let state = ChainState {
+            rng: params
+                .seed
+                .map(fastrand::Rng::with_seed)
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        Self { params, state }
+    }
+}
+
+impl Sampler for ChainSampler {
+    fn init(&mut self, model_tokens: &[u16], penalty_free_tokens: Arc<HashSet<u16>>) {
+        let ChainSampler { params, state } = self;
+        state
+            .penalty
+            .init(&params.penalty, model_tokens, penalty_free_tokens);
+    }
+
+    fn transform(&self, output: &mut [f32], bias: &HashMap<u16, f32>) {
+        let ChainSampler { params, state } = self;
+        for stage in &params.samplers {
+            match stage {
+                Stage::Penalty => state.penalty.transform(output),
+                Stage::Bias => super::apply_bias(output, bias),
+                Stage::Temperature => {
+                    let temperature = params.temperature.max(f32::EPSILON);
+                    output.iter_mut().for_each(|x| *x /= temperature);
+                }
+                Stage::TopK if params.top_k > 0 => {
+                    truncate(output, |probs| top_k::candidates(probs, params.top_k))
+                }
+                Stage::TopP if params.top_p < 1.0 => {
+                    truncate(output, |probs| nucleus::candidates(probs, params.top_p))
+                }
+                Stage::MinP if params.min_p > 0.0 => {
+                    truncate(output, |probs| min_p::candidates(probs, params.min_p))
+                }
+                Stage::Typical if params.typical_p < 1.0 => {
+                    truncate(output, |probs| typical::candidates(probs, params.typical_p))
+                }
+                Stage::TailFree if params.tfs_z < 1.0 => {
+                    truncate(output, |probs| tail_free::candidates(probs, params.tfs_z))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn sample(&mut self, probs: &[f32]) -> u16 {
+        let ChainSampler { params, state } = self;
+        let candidates = probs
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, x)| *x > 0.0)
+            .collect_vec();
+        let token = super::draw(&candidates, 1.0, &mut state.rng);
+        if params.samplers.contains(&Stage::Penalty) {
+            state.penalty.update(&params.penalty, token);
+        }
+        token
+    }
+}
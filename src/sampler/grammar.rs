@@ -0,0 +1,708 @@
+use std::sync::Arc;
+
+use itertools::Itertools;
+use serde_json::Value;
+
+/// Maximum number of consecutive whitespaces allowed between JSON tokens.
+const MAX_WHITESPACE: usize = 16;
+
+/// A compiled subset of JSON schema that constrains the generated value.
+#[derive(Debug, Clone)]
+pub enum Schema {
+    Any,
+    Object {
+        properties: Vec<(String, Arc<Schema>)>,
+        required: Vec<String>,
+        additional: bool,
+    },
+    Array(Arc<Schema>),
+    String,
+    Number {
+        integer: bool,
+    },
+    Boolean,
+    Null,
+    /// Serialized JSON values the output must be one of.
+    Enum(Vec<Vec<u8>>),
+}
+
+impl Schema {
+    /// An object with arbitrary keys and values.
+    pub fn object() -> Self {
+        Self::Object {
+            properties: vec![],
+            required: vec![],
+            additional: true,
+        }
+    }
+
+    /// Compile a JSON schema. Supported keywords are `type`, `properties`, `required`,
+    /// `additionalProperties`, `items`, `enum` and `const`; anything else accepts any value.
+    pub fn new(schema: &Value) -> Self {
+        if let Some(value) = schema.get("const") {
+            return Self::Enum(vec![serde_json::to_vec(value).unwrap_or_default()]);
+        }
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            let values = values
+                .iter()
+                .filter_map(|value| serde_json::to_vec(value).ok())
+                .collect();
+            return Self::Enum(values);
+        }
+
+        let ty = match schema.get("type") {
+            Some(Value::String(ty)) => ty.as_str(),
+            Some(Value::Array(types)) if types.len() == 1 => types[0].as_str().unwrap_or_default(),
+            None if schema.get("properties").is_some() => "object",
+            None if schema.get("items").is_some() => "array",
+            _ => "",
+        };
+        match ty {
+            "object" => {
+                let properties = match schema.get("properties") {
+                    Some(Value::Object(properties)) => properties
+                        .iter()
+                        .map(|(key, value)| (key.clone(), Arc::new(Self::new(value))))
+                        .collect(),
+                    _ => vec![],
+                };
+                let required = match schema.get("required") {
+                    Some(Value::Array(keys)) => keys
+                        .iter()
+                        .filter_map(|key| key.as_str())
+                        .map(String::from)
+                        .collect(),
+                    _ => vec![],
+                };
+                // without any property listed, the object can have arbitrary keys
+                let additional = match schema.get("additionalProperties") {
+                    Some(Value::Bool(additional)) => *additional,
+                    Some(_) => true,
+                    None => properties.is_empty(),
+                };
+                Self::Object {
+                    properties,
+                    required,
+                    additional,
+                }
+            }
+            "array" => {
+                let item = schema.get("items").map(Self::new).unwrap_or(Self::Any);
+                Self::Array(Arc::new(item))
+            }
+            "string" => Self::String,
+            "number" => Self::Number { integer: false },
+            "integer" => Self::Number { integer: true },
+            "boolean" => Self::Boolean,
+            "null" => Self::Null,
+            _ => Self::Any,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ObjectState {
+    /// Expecting `{`.
+    Open,
+    /// After `{`, expecting a key or `}`.
+    Start,
+    /// After `,`, expecting a key.
+    Comma,
+    /// After a key, expecting `:`.
+    Colon,
+    /// After a value, expecting `,` or `}`.
+    Next,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArrayState {
+    /// Expecting `[`.
+    Open,
+    /// After `[`, expecting an item or `]`.
+    Start,
+    /// After `,`, expecting an item.
+    Comma,
+    /// After an item, expecting `,` or `]`.
+    Next,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StringState {
+    Open,
+    Body,
+    Escape,
+    Unicode(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NumberState {
+    Begin,
+    Minus,
+    Zero,
+    Integer,
+    Dot,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits,
+}
+
+#[derive(Debug, Clone)]
+struct KeyState {
+    /// Keys that are allowed here, or `None` if any key is allowed.
+    allowed: Option<Arc<Vec<String>>>,
+    buffer: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+enum Frame {
+    /// Expecting a value, possibly preceded by whitespaces.
+    Value(Arc<Schema>),
+    Object {
+        schema: Arc<Schema>,
+        keys: Vec<String>,
+        state: ObjectState,
+    },
+    Array {
+        item: Arc<Schema>,
+        state: ArrayState,
+    },
+    String {
+        state: StringState,
+        key: Option<KeyState>,
+    },
+    Number {
+        state: NumberState,
+        integer: bool,
+    },
+    Literal {
+        candidates: Vec<Vec<u8>>,
+        index: usize,
+    },
+}
+
+enum Transition {
+    /// The byte is a whitespace between JSON tokens.
+    Space,
+    /// The byte is consumed by the current frame.
+    Consume,
+    /// The byte is not allowed.
+    Reject,
+    /// Replace the current frame and feed the byte to the new one.
+    Replace(Frame),
+    /// Push a new frame and feed the byte to it.
+    Push(Frame),
+    /// Consume the byte and push a new frame.
+    ConsumePush(Frame),
+    /// Consume the byte, and the current frame is finished.
+    ConsumePop,
+    /// The current frame is finished before the byte, which is fed to the parent frame.
+    Pop,
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+impl Frame {
+    fn literal(candidates: &[&str]) -> Self {
+        let candidates = candidates.iter().map(|x| x.as_bytes().to_vec()).collect();
+        Self::Literal {
+            candidates,
+            index: 0,
+        }
+    }
+
+    /// Select the frame of the value according to its first byte.
+    fn value(schema: &Arc<Schema>, byte: u8) -> Transition {
+        let frame = match (schema.as_ref(), byte) {
+            (Schema::Any, b'{') => Frame::Object {
+                schema: Arc::new(Schema::object()),
+                keys: vec![],
+                state: ObjectState::Open,
+            },
+            (Schema::Object { .. }, b'{') => Frame::Object {
+                schema: schema.clone(),
+                keys: vec![],
+                state: ObjectState::Open,
+            },
+            (Schema::Any, b'[') => Frame::Array {
+                item: Arc::new(Schema::Any),
+                state: ArrayState::Open,
+            },
+            (Schema::Array(item), b'[') => Frame::Array {
+                item: item.clone(),
+                state: ArrayState::Open,
+            },
+            (Schema::Any | Schema::String, b'"') => Frame::String {
+                state: StringState::Open,
+                key: None,
+            },
+            (Schema::Any, b'-' | b'0'..=b'9') => Frame::Number {
+                state: NumberState::Begin,
+                integer: false,
+            },
+            (Schema::Number { integer }, b'-' | b'0'..=b'9') => Frame::Number {
+                state: NumberState::Begin,
+                integer: *integer,
+            },
+            (Schema::Any, b't' | b'f' | b'n') => Frame::literal(&["true", "false", "null"]),
+            (Schema::Boolean, b't' | b'f') => Frame::literal(&["true", "false"]),
+            (Schema::Null, b'n') => Frame::literal(&["null"]),
+            (Schema::Enum(candidates), _) => Frame::Literal {
+                candidates: candidates.clone(),
+                index: 0,
+            },
+            _ => return Transition::Reject,
+        };
+        Transition::Replace(frame)
+    }
+
+    fn step(&mut self, byte: u8) -> Transition {
+        match self {
+            Frame::Value(_) if is_whitespace(byte) => Transition::Space,
+            Frame::Value(schema) => Frame::value(schema, byte),
+            Frame::Object {
+                schema,
+                keys,
+                state,
+            } => {
+                let Schema::Object {
+                    properties,
+                    required,
+                    additional,
+                } = schema.as_ref()
+                else {
+                    unreachable!()
+                };
+                // keys that can still be used, or `None` if any key is allowed
+                let remain = (!additional).then(|| {
+                    properties
+                        .iter()
+                        .map(|(key, _)| key)
+                        .filter(|key| !keys.contains(key))
+                        .cloned()
+                        .collect_vec()
+                });
+                let satisfied = required.iter().all(|key| keys.contains(key));
+
+                match (*state, byte) {
+                    (ObjectState::Open, b'{') => {
+                        *state = ObjectState::Start;
+                        Transition::Consume
+                    }
+                    (ObjectState::Open, _) => Transition::Reject,
+                    (_, byte) if is_whitespace(byte) => Transition::Space,
+                    (ObjectState::Start | ObjectState::Comma, b'"') => {
+                        if remain.as_ref().is_some_and(|remain| remain.is_empty()) {
+                            return Transition::Reject;
+                        }
+                        *state = ObjectState::Colon;
+                        Transition::Push(Frame::String {
+                            state: StringState::Open,
+                            key: Some(KeyState {
+                                allowed: remain.map(Arc::new),
+                                buffer: vec![],
+                            }),
+                        })
+                    }
+                    (ObjectState::Start | ObjectState::Next, b'}') if satisfied => {
+                        Transition::ConsumePop
+                    }
+                    (ObjectState::Colon, b':') => {
+                        let value = keys
+                            .last()
+                            .and_then(|key| properties.iter().find(|(x, _)| x == key))
+                            .map(|(_, value)| value.clone())
+                            .unwrap_or(Arc::new(Schema::Any));
+                        *state = ObjectState::Next;
+                        Transition::ConsumePush(Frame::Value(value))
+                    }
+                    (ObjectState::Next, b',') => {
+                        if remain.is_some_and(|remain| remain.is_empty()) {
+                            return Transition::Reject;
+                        }
+                        *state = ObjectState::Comma;
+                        Transition::Consume
+                    }
+                    _ => Transition::Reject,
+                }
+            }
+            Frame::Array { item, state } => match (*state, byte) {
+                (ArrayState::Open, b'[') => {
+                    *state = ArrayState::Start;
+                    Transition::Consume
+                }
+                (ArrayState::Open, _) => Transition::Reject,
+                (_, byte) if is_whitespace(byte) => Transition::Space,
+                (ArrayState::Start | ArrayState::Next, b']') => Transition::ConsumePop,
+                (ArrayState::Start | ArrayState::Comma, _) => {
+                    *state = ArrayState::Next;
+                    Transition::Push(Frame::Value(item.clone()))
+                }
+                (ArrayState::Next, b',') => {
+                    *state = ArrayState::Comma;
+                    Transition::Consume
+                }
+                _ => Transition::Reject,
+            },
+            Frame::String { state, key } => {
+                let next = match (*state, byte) {
+                    (StringState::Open, b'"') => StringState::Body,
+                    (StringState::Body, b'"') => {
+                        return match key {
+                            Some(KeyState {
+                                allowed: Some(allowed),
+                                buffer,
+                            }) if !allowed.iter().any(|x| x.as_bytes() == buffer.as_slice()) => {
+                                Transition::Reject
+                            }
+                            _ => Transition::ConsumePop,
+                        };
+                    }
+                    (StringState::Body, b'\\') => StringState::Escape,
+                    (StringState::Body, 0x00..=0x1f) => return Transition::Reject,
+                    (StringState::Body, _) => StringState::Body,
+                    (
+                        StringState::Escape,
+                        b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't',
+                    ) => StringState::Body,
+                    (StringState::Escape, b'u') => StringState::Unicode(4),
+                    (StringState::Unicode(1), byte) if byte.is_ascii_hexdigit() => {
+                        StringState::Body
+                    }
+                    (StringState::Unicode(n), byte) if byte.is_ascii_hexdigit() => {
+                        StringState::Unicode(n - 1)
+                    }
+                    _ => return Transition::Reject,
+                };
+
+                if let (
+                    StringState::Body | StringState::Escape | StringState::Unicode(_),
+                    Some(key),
+                ) = (*state, key)
+                {
+                    key.buffer.push(byte);
+                    if let Some(allowed) = &key.allowed {
+                        if !allowed
+                            .iter()
+                            .any(|x| x.as_bytes().starts_with(&key.buffer))
+                        {
+                            return Transition::Reject;
+                        }
+                    }
+                }
+
+                *state = next;
+                Transition::Consume
+            }
+            Frame::Number { state, integer } => {
+                use NumberState::*;
+                let next = match (*state, byte) {
+                    (Begin, b'-') => Minus,
+                    (Begin | Minus, b'0') => Zero,
+                    (Begin | Minus, b'1'..=b'9') => Integer,
+                    (Integer, b'0'..=b'9') => Integer,
+                    (Zero | Integer, b'.') if !*integer => Dot,
+                    (Dot | Fraction, b'0'..=b'9') => Fraction,
+                    (Zero | Integer | Fraction, b'e' | b'E') if !*integer => Exponent,
+                    (Exponent, b'+' | b'-') => ExponentSign,
+                    (Exponent | ExponentSign | ExponentDigits, b'0'..=b'9') => ExponentDigits,
+                    (Zero | Integer | Fraction | ExponentDigits, _) => return Transition::Pop,
+                    _ => return Transition::Reject,
+                };
+                *state = next;
+                Transition::Consume
+            }
+            Frame::Literal { candidates, index } => {
+                let index_next = *index + 1;
+                if candidates.iter().any(|x| x.get(*index) == Some(&byte)) {
+                    candidates.retain(|x| x.get(*index) == Some(&byte));
+                    *index = index_next;
+                    match candidates.iter().all(|x| x.len() == index_next) {
+                        true => Transition::ConsumePop,
+                        false => Transition::Consume,
+                    }
+                } else if candidates.iter().any(|x| x.len() == *index) {
+                    Transition::Pop
+                } else {
+                    Transition::Reject
+                }
+            }
+        }
+    }
+}
+
+/// A byte trie over the vocabulary, so that all tokens can be checked against a grammar in one pass.
+#[derive(Debug, Clone)]
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u16>,
+}
+
+impl TokenTrie {
+    /// Build the trie from the vocabulary, given as `(token, bytes)` pairs.
+    pub fn new(words: impl IntoIterator<Item = (u16, Vec<u8>)>) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (token, word) in words {
+            let mut index = 0;
+            for &byte in &word {
+                index = match nodes[index].children.iter().find(|(x, _)| *x == byte) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        let child = nodes.len();
+                        nodes[index].children.push((byte, child));
+                        nodes.push(TrieNode::default());
+                        child
+                    }
+                };
+            }
+            // tokens that decode into nothing are never allowed
+            if index > 0 {
+                nodes[index].tokens.push(token);
+            }
+        }
+        Self { nodes }
+    }
+}
+
+/// Tracks the output against a JSON grammar, and masks out tokens that would make the output invalid.
+///
+/// Frames are shared between clones and copied only when they change,
+/// so that the grammar can be cheaply forked for every byte tried in [`Grammar::mask`].
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    stack: Vec<Arc<Frame>>,
+    whitespace: usize,
+    /// Whether the output started with a value. Trailing whitespaces are allowed once it is finished.
+    started: bool,
+}
+
+impl Grammar {
+    pub fn new(schema: Schema) -> Self {
+        Self {
+            stack: vec![Arc::new(Frame::Value(Arc::new(schema)))],
+            whitespace: 0,
+            started: false,
+        }
+    }
+
+    /// Feed one byte of the output. Returns `false` if the byte violates the grammar.
+    pub fn feed(&mut self, byte: u8) -> bool {
+        loop {
+            let Some(frame) = self.stack.last_mut() else {
+                // a top-level number only ends at the byte after it, so the value is followed by whitespaces
+                return match self.started && is_whitespace(byte) {
+                    true => {
+                        self.whitespace += 1;
+                        self.whitespace <= MAX_WHITESPACE
+                    }
+                    false => false,
+                };
+            };
+            match Arc::make_mut(frame).step(byte) {
+                Transition::Space => {
+                    self.whitespace += 1;
+                    return self.whitespace <= MAX_WHITESPACE;
+                }
+                Transition::Consume => break,
+                Transition::Reject => return false,
+                Transition::Replace(frame) => {
+                    self.stack.pop();
+                    self.stack.push(Arc::new(frame));
+                }
+                Transition::Push(frame) => self.stack.push(Arc::new(frame)),
+                Transition::ConsumePush(frame) => {
+                    self.stack.push(Arc::new(frame));
+                    break;
+                }
+                Transition::ConsumePop => {
+                    self.pop();
+                    break;
+                }
+                Transition::Pop => self.pop(),
+            }
+        }
+        self.whitespace = 0;
+        self.started = true;
+        true
+    }
+
+    /// Feed bytes of the output. Returns `false` if any of them violates the grammar.
+    pub fn feed_bytes(&mut self, bytes: &[u8]) -> bool {
+        bytes.iter().all(|&byte| self.feed(byte))
+    }
+
+    /// Returns `true` if a whole JSON value has been output.
+    pub fn is_complete(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    fn pop(&mut self) {
+        // a finished key is recorded by the object it belongs to
+        let Some(frame) = self.stack.pop() else {
+            return;
+        };
+        if let Frame::String {
+            key: Some(KeyState { buffer, .. }),
+            ..
+        } = frame.as_ref()
+        {
+            if let Some(frame) = self.stack.last_mut() {
+                if let Frame::Object { keys, .. } = Arc::make_mut(frame) {
+                    keys.push(String::from_utf8_lossy(buffer).into());
+                }
+            }
+        }
+    }
+
+    /// Mask out all tokens that would violate the grammar.
+    pub fn mask(&self, trie: &TokenTrie, output: &mut [f32]) {
+        let mut allowed = vec![false; output.len()];
+        let mut stack = vec![(0, self.clone())];
+        while let Some((index, grammar)) = stack.pop() {
+            let node = &trie.nodes[index];
+            for &token in &node.tokens {
+                if let Some(allowed) = allowed.get_mut(token as usize) {
+                    *allowed = true;
+                }
+            }
+            for &(byte, child) in &node.children {
+                let mut grammar = grammar.clone();
+                if grammar.feed(byte) {
+                    stack.push((child, grammar));
+                }
+            }
+        }
+
+        // leave the output untouched if nothing is allowed, so that the model can still make progress
+        if allowed.iter().any(|&x| x) {
+            for (x, allowed) in output.iter_mut().zip(allowed) {
+                if !allowed {
+                    *x = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn accepts(schema: Value, text: &str) -> bool {
+        let mut grammar = Grammar::new(Schema::new(&schema));
+        grammar.feed_bytes(text.as_bytes()) && grammar.is_complete()
+    }
+
+    #[test]
+    fn object() {
+        assert!(accepts(json!({}), r#"{"a": 1, "b": [true, null]}"#));
+        assert!(accepts(json!({"type": "object"}), "{ }"));
+        assert!(!accepts(json!({"type": "object"}), r#"{"a" 1}"#));
+        assert!(!accepts(json!({"type": "object"}), r#"{"a": 1,}"#));
+
+        let schema = json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}, "age": {"type": "integer"}},
+            "required": ["name"],
+        });
+        assert!(accepts(schema.clone(), r#"{"name": "x", "age": 3}"#));
+        assert!(accepts(schema.clone(), r#"{"name": "x"}"#));
+        assert!(!accepts(schema.clone(), r#"{"age": 3}"#));
+        assert!(!accepts(schema.clone(), r#"{"name": 3}"#));
+        assert!(!accepts(schema, r#"{"name": "x", "other": 1}"#));
+    }
+
+    #[test]
+    fn array() {
+        let schema = json!({"type": "array", "items": {"type": "number"}});
+        assert!(accepts(schema.clone(), "[]"));
+        assert!(accepts(schema.clone(), "[1, -2.5, 3e10]"));
+        assert!(!accepts(schema.clone(), r#"[1, "2"]"#));
+        assert!(!accepts(schema, "[1 2]"));
+    }
+
+    #[test]
+    fn string() {
+        let schema = json!({"type": "string"});
+        assert!(accepts(schema.clone(), r#""plain""#));
+        assert!(accepts(schema.clone(), r#""quote \" and \\ and \n""#));
+        assert!(accepts(schema.clone(), r#""é""#));
+        assert!(!accepts(schema.clone(), r#""\x""#));
+        assert!(!accepts(schema.clone(), r#""\u00g0""#));
+        assert!(!accepts(schema, "\"line\nbreak\""));
+    }
+
+    #[test]
+    fn number() {
+        let schema = json!({"type": "number"});
+        assert!(accepts(schema.clone(), "0 "));
+        assert!(accepts(schema.clone(), "-12.5e-3\n"));
+        assert!(!accepts(schema.clone(), "01"));
+        assert!(!accepts(schema.clone(), "1."));
+
+        // a top-level number is complete once a whitespace follows it
+        let mut grammar = Grammar::new(Schema::new(&schema));
+        assert!(grammar.feed_bytes(b"42"));
+        assert!(!grammar.is_complete());
+        assert!(grammar.feed(b'\n'));
+        assert!(grammar.is_complete());
+        assert!(!grammar.feed(b'1'));
+
+        let schema = json!({"type": "integer"});
+        assert!(accepts(schema.clone(), "42 "));
+        assert!(!accepts(schema, "4.2 "));
+    }
+
+    #[test]
+    fn enums() {
+        let schema = json!({"enum": ["red", "green", 3]});
+        assert!(accepts(schema.clone(), r#""red""#));
+        assert!(accepts(schema.clone(), r#""green""#));
+        assert!(!accepts(schema.clone(), r#""blue""#));
+        assert!(!accepts(schema, "4"));
+
+        let schema = json!({"type": "object", "properties": {"color": {"const": "red"}}});
+        assert!(accepts(schema.clone(), r#"{"color": "red"}"#));
+        assert!(!accepts(schema, r#"{"color": "green"}"#));
+    }
+
+    #[test]
+    fn mask() {
+        let words = ["{", "}", "\"", "a", "\":", " 1", "1}", "x"];
+        let trie = TokenTrie::new(
+            words
+                .iter()
+                .enumerate()
+                .map(|(token, word)| (token as u16, word.as_bytes().to_vec())),
+        );
+
+        let mut grammar = Grammar::new(Schema::object());
+        let allowed = |grammar: &Grammar| {
+            let mut output = vec![0.0; words.len()];
+            grammar.mask(&trie, &mut output);
+            output
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| x.is_finite())
+                .map(|(token, _)| words[token])
+                .collect_vec()
+        };
+        assert_eq!(allowed(&grammar), ["{"]);
+
+        assert!(grammar.feed_bytes(b"{"));
+        assert_eq!(allowed(&grammar), ["}", "\"", "\":"]);
+
+        // masking leaves the grammar untouched
+        assert!(grammar.feed_bytes(b"\"a\":"));
+        assert_eq!(allowed(&grammar), ["{", "\"", "\":", " 1", "1}"]);
+    }
+}
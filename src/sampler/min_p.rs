@@ -0,0 +1,38 @@
+use derivative::Derivative;
+use itertools::Itertools;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::truncate::{DrawParams, Truncate, TruncateSampler};
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+pub struct MinPParams {
+    /// Minimum probability of a token to be kept, relative to that of the most likely token.
+    #[derivative(Default(value = "0.05"))]
+    pub min_p: f32,
+    #[serde(flatten)]
+    pub draw: DrawParams,
+}
+
+/// Keep tokens with probabilities no less than `min_p` times that of the most likely token.
+pub fn candidates(probs: &[f32], min_p: f32) -> Vec<(usize, f32)> {
+    let sorted = super::sort_probs(probs);
+    let threshold = sorted.first().map(|(_, x)| x * min_p).unwrap_or_default();
+    sorted
+        .into_iter()
+        .take_while(|(_, x)| *x >= threshold)
+        .collect_vec()
+}
+
+impl Truncate for MinPParams {
+    fn draw(&self) -> &DrawParams {
+        &self.draw
+    }
+
+    fn candidates(&self, probs: &[f32]) -> Vec<(usize, f32)> {
+        candidates(probs, self.min_p)
+    }
+}
+
+pub type MinPSampler = TruncateSampler<MinPParams>;
@@ -1,9 +1,18 @@
-use super::Sampler;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
 use derivative::Derivative;
 use itertools::Itertools;
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
 
+use super::{
+    penalty::{PenaltyParams, PenaltyState},
+    Sampler,
+};
+
 #[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
 pub struct MirostatParams {
@@ -15,6 +24,11 @@ pub struct MirostatParams {
     #[derivative(Default(value = "128"))]
     #[serde(default = "default_threshold")]
     pub threshold: usize,
+    #[serde(flatten)]
+    pub penalty: PenaltyParams,
+    /// Seed of the random number generator. Results are reproducible if this is set.
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
 fn default_threshold() -> usize {
@@ -24,6 +38,8 @@ fn default_threshold() -> usize {
 #[derive(Debug, Clone, Default)]
 pub struct MirostatState {
     pub max_surprise: f32,
+    pub penalty: PenaltyState,
+    pub rng: fastrand::Rng,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -36,6 +52,11 @@ impl MirostatSampler {
     pub fn new(params: MirostatParams) -> Self {
         let state = MirostatState {
             max_surprise: params.tau * 2.0,
+            rng: params
+                .seed
+                .map(fastrand::Rng::with_seed)
+                .unwrap_or_default(),
+            ..Default::default()
         };
         Self { params, state }
     }
@@ -63,6 +84,18 @@ impl MirostatSampler {
 }
 
 impl Sampler for MirostatSampler {
+    fn init(&mut self, model_tokens: &[u16], penalty_free_tokens: Arc<HashSet<u16>>) {
+        let MirostatSampler { params, state } = self;
+        state
+            .penalty
+            .init(&params.penalty, model_tokens, penalty_free_tokens);
+    }
+
+    fn transform(&self, output: &mut [f32], bias: &HashMap<u16, f32>) {
+        self.state.penalty.transform(output);
+        super::apply_bias(output, bias);
+    }
+
     fn sample(&mut self, probs: &[f32]) -> u16 {
         let sorted = probs
             .iter()
@@ -80,7 +113,7 @@ impl Sampler for MirostatSampler {
         let k = k.min(probs.len() - 1);
 
         let sum = sorted.get(k).map(|&(_, cum, _)| cum).unwrap_or_default();
-        let rand = fastrand::f32() * sum;
+        let rand = self.state.rng.f32() * sum;
         let (token, _, prob) = sorted
             .into_iter()
             .find_or_first(|&(_, cum, _)| rand <= cum)
@@ -90,6 +123,8 @@ impl Sampler for MirostatSampler {
         let error_surprise = token_surprise - self.params.tau;
         self.state.max_surprise -= self.params.rate * error_surprise;
 
-        token as u16
+        let token = token as u16;
+        self.state.penalty.update(&self.params.penalty, token);
+        token
     }
 }
@@ -1,12 +1,132 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use itertools::Itertools;
+
+pub mod chain;
+pub mod grammar;
+pub mod min_p;
 pub mod mirostat;
 pub mod nucleus;
+pub mod penalty;
+pub mod tail_free;
+pub mod top_k;
+pub mod truncate;
+pub mod typical;
 
 #[allow(unused_variables)]
 pub trait Sampler {
-    /// Initialize the sampler state.
-    fn init(&mut self, model_tokens: &[u16]) {}
-    /// Update the raw model output.
-    fn transform(&self, output: &mut [f32]) {}
+    /// Initialize the sampler state, with tokens exempted from penalties unless the request specifies its own.
+    fn init(&mut self, model_tokens: &[u16], penalty_free_tokens: Arc<HashSet<u16>>) {}
+    /// Update the raw model output, with logit bias given by the request.
+    fn transform(&self, output: &mut [f32], bias: &HashMap<u16, f32>) {
+        apply_bias(output, bias);
+    }
     /// Select one token from the distribution, and also update the state.
     fn sample(&mut self, probs: &[f32]) -> u16;
 }
+
+/// Add logit bias to the raw model output.
+pub fn apply_bias(output: &mut [f32], bias: &HashMap<u16, f32>) {
+    for (token, bias) in bias.iter() {
+        output[*token as usize] += *bias
+    }
+}
+
+/// Draw one token from the candidates of `(token, probability)`, after probabilities are sharpened by `temperature`.
+pub fn draw(candidates: &[(usize, f32)], temperature: f32, rng: &mut fastrand::Rng) -> u16 {
+    let candidates = candidates
+        .iter()
+        .map(|&(id, x)| (id, x.powf(1.0 / temperature)))
+        .collect_vec();
+
+    let sum: f32 = candidates.iter().map(|(_, x)| x).sum();
+    let candidates = candidates
+        .into_iter()
+        .map(|(id, x)| (id, x / sum))
+        .scan((0, 0.0), |(_, cum), (id, x)| {
+            *cum += x;
+            Some((id, *cum))
+        })
+        .collect_vec();
+
+    let rand = rng.f32();
+    let token = candidates
+        .into_iter()
+        .find_or_first(|&(_, cum)| rand <= cum)
+        .map(|(id, _)| id)
+        .unwrap_or_default();
+    token as u16
+}
+
+/// Sort the distribution into `(token, probability)` pairs, from the most to the least likely.
+pub fn sort_probs(probs: &[f32]) -> Vec<(usize, f32)> {
+    probs
+        .iter()
+        .copied()
+        .enumerate()
+        .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
+        .collect()
+}
+
+/// Compute probabilities from the raw model output.
+pub fn softmax(output: &[f32]) -> Vec<f32> {
+    let max = output.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp = output.iter().map(|x| (x - max).exp()).collect_vec();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|x| x / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sorted by probability, the tokens are 1, 3, 2, 0 and 4.
+    const PROBS: [f32; 5] = [0.1, 0.4, 0.2, 0.25, 0.05];
+
+    fn ids(candidates: Vec<(usize, f32)>) -> Vec<usize> {
+        candidates.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn top_k() {
+        assert_eq!(ids(top_k::candidates(&PROBS, 2)), vec![1, 3]);
+        assert_eq!(ids(top_k::candidates(&PROBS, 10)), vec![1, 3, 2, 0, 4]);
+        // 0 keeps everything
+        assert_eq!(ids(top_k::candidates(&PROBS, 0)), vec![1, 3, 2, 0, 4]);
+    }
+
+    #[test]
+    fn min_p() {
+        // the threshold is inclusive
+        assert_eq!(ids(min_p::candidates(&PROBS, 0.5)), vec![1, 3, 2]);
+        assert_eq!(ids(min_p::candidates(&PROBS, 0.7)), vec![1]);
+        assert_eq!(ids(min_p::candidates(&PROBS, 0.0)).len(), PROBS.len());
+    }
+
+    #[test]
+    fn typical() {
+        // the entropy is about 1.415, so the tokens closest to it in surprise are 3, 2, 1, 0 and 4
+        assert_eq!(ids(typical::candidates(&PROBS, 0.3)), vec![3, 2]);
+        assert_eq!(ids(typical::candidates(&PROBS, 0.5)), vec![3, 2, 1]);
+        assert_eq!(ids(typical::candidates(&PROBS, 1.0)).len(), PROBS.len());
+    }
+
+    #[test]
+    fn tail_free() {
+        // normalized second derivatives are 0.5, 0.25 and 0.25, so their cumulative sums are 0.5, 0.75 and 1.0
+        assert_eq!(ids(tail_free::candidates(&PROBS, 0.6)), vec![1]);
+        assert_eq!(ids(tail_free::candidates(&PROBS, 0.9)), vec![1, 3]);
+        // at least one token is always kept
+        assert_eq!(ids(tail_free::candidates(&PROBS, 0.0)), vec![1]);
+        assert_eq!(ids(tail_free::candidates(&PROBS, 1.0)).len(), PROBS.len());
+    }
+
+    #[test]
+    fn nucleus() {
+        assert_eq!(ids(nucleus::candidates(&PROBS, 0.5)), vec![1, 3]);
+        assert_eq!(ids(nucleus::candidates(&PROBS, 0.3)), vec![1]);
+    }
+}
@@ -1,11 +1,17 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use derivative::Derivative;
 use itertools::Itertools;
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
 
-use super::Sampler;
+use super::{
+    penalty::{PenaltyParams, PenaltyState},
+    Sampler,
+};
 
 #[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
@@ -14,32 +20,35 @@ pub struct NucleusParams {
     pub top_p: f32,
     #[derivative(Default(value = "1.0"))]
     pub temperature: f32,
-    #[derivative(Default(value = "0.3"))]
-    #[serde(default = "default_presence_penalty")]
-    pub presence_penalty: f32,
-    #[derivative(Default(value = "0.3"))]
-    #[serde(default = "default_frequency_penalty")]
-    pub frequency_penalty: f32,
-    #[derivative(Default(value = "0.99654026"))]
-    #[serde(default = "default_penalty_decay")]
-    pub penalty_decay: f32,
+    #[serde(flatten)]
+    pub penalty: PenaltyParams,
+    /// Seed of the random number generator. Results are reproducible if this is set.
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
-fn default_presence_penalty() -> f32 {
-    NucleusParams::default().presence_penalty
-}
-
-fn default_frequency_penalty() -> f32 {
-    NucleusParams::default().frequency_penalty
-}
-
-fn default_penalty_decay() -> f32 {
-    NucleusParams::default().penalty_decay
+/// Keep the most likely tokens, until their cumulative probability exceeds `top_p`.
+pub fn candidates(probs: &[f32], top_p: f32) -> Vec<(usize, f32)> {
+    probs
+        .iter()
+        .enumerate()
+        .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
+        .scan((0, 0.0, 0.0), |(_, cum, _), (id, x)| {
+            if *cum > top_p {
+                None
+            } else {
+                *cum += x;
+                Some((id, *cum, *x))
+            }
+        })
+        .map(|(id, _, x)| (id, x))
+        .collect_vec()
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct NucleusState {
-    pub penalties: HashMap<u16, f32>,
+    pub penalty: PenaltyState,
+    pub rng: fastrand::Rng,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -50,81 +59,35 @@ pub struct NucleusSampler {
 
 impl NucleusSampler {
     pub fn new(params: NucleusParams) -> Self {
-        Self {
-            params,
-            state: Default::default(),
-        }
+        let state = NucleusState {
+            rng: params
+                .seed
+                .map(fastrand::Rng::with_seed)
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        Self { params, state }
     }
 }
 
 impl Sampler for NucleusSampler {
-    fn init(&mut self, model_tokens: &[u16]) {
+    fn init(&mut self, model_tokens: &[u16], penalty_free_tokens: Arc<HashSet<u16>>) {
         let NucleusSampler { params, state } = self;
-        for (index, token) in model_tokens.iter().rev().enumerate() {
-            let ap = params.presence_penalty;
-            let af = params.frequency_penalty;
-            let ad = params.penalty_decay;
-            let mut penalty = state.penalties.remove(token).unwrap_or(ap);
-            penalty += af * ad.powf(index as f32);
-            state.penalties.insert(*token, penalty);
-        }
+        state
+            .penalty
+            .init(&params.penalty, model_tokens, penalty_free_tokens);
     }
 
-    fn transform(&self, output: &mut [f32]) {
-        self.state
-            .penalties
-            .iter()
-            // .filter(|(token, _)| !penalty_free_tokens.contains(token))
-            .for_each(|(token, penalty)| output[*token as usize] -= penalty)
+    fn transform(&self, output: &mut [f32], bias: &HashMap<u16, f32>) {
+        self.state.penalty.transform(output);
+        super::apply_bias(output, bias);
     }
 
     fn sample(&mut self, probs: &[f32]) -> u16 {
         let NucleusSampler { params, state } = self;
-
-        let sorted = probs
-            .iter()
-            .enumerate()
-            .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
-            .scan((0, 0.0, 0.0), |(_, cum, _), (id, x)| {
-                if *cum > params.top_p {
-                    None
-                } else {
-                    *cum += x;
-                    Some((id, *cum, *x))
-                }
-            })
-            .map(|(id, _, x)| (id, x.powf(1.0 / params.temperature)))
-            .collect_vec();
-
-        let sum: f32 = sorted.iter().map(|(_, x)| x).sum();
-        let sorted = sorted
-            .into_iter()
-            .map(|(id, x)| (id, x / sum))
-            .scan((0, 0.0), |(_, cum), (id, x)| {
-                *cum += x;
-                Some((id, *cum))
-            })
-            .collect_vec();
-
-        let rand = fastrand::f32();
-        let token = sorted
-            .into_iter()
-            .find_or_first(|&(_, cum)| rand <= cum)
-            .map(|(id, _)| id)
-            .unwrap_or_default();
-        let token = token as u16;
-
-        state
-            .penalties
-            .iter_mut()
-            .for_each(|(_, penalty)| *penalty *= params.penalty_decay);
-
-        let penalty = match state.penalties.get(&token) {
-            Some(penalty) => penalty + params.frequency_penalty,
-            None => params.presence_penalty,
-        };
-        state.penalties.insert(token, penalty);
-
+        let candidates = candidates(probs, params.top_p);
+        let token = super::draw(&candidates, params.temperature, &mut state.rng);
+        state.penalty.update(&params.penalty, token);
         token
     }
 }
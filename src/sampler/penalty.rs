@@ -0,0 +1,89 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use derivative::Derivative;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+pub struct PenaltyParams {
+    #[derivative(Default(value = "0.3"))]
+    #[serde(default = "default_presence_penalty")]
+    pub presence_penalty: f32,
+    #[derivative(Default(value = "0.3"))]
+    #[serde(default = "default_frequency_penalty")]
+    pub frequency_penalty: f32,
+    #[derivative(Default(value = "0.99654026"))]
+    #[serde(default = "default_penalty_decay")]
+    pub penalty_decay: f32,
+    /// Tokens exempted from penalties. Overrides the list in config if set.
+    #[serde(default)]
+    pub penalty_free_tokens: Option<Vec<u16>>,
+}
+
+fn default_presence_penalty() -> f32 {
+    PenaltyParams::default().presence_penalty
+}
+
+fn default_frequency_penalty() -> f32 {
+    PenaltyParams::default().frequency_penalty
+}
+
+fn default_penalty_decay() -> f32 {
+    PenaltyParams::default().penalty_decay
+}
+
+/// Presence and frequency penalties of tokens, which decay over time.
+#[derive(Debug, Default, Clone)]
+pub struct PenaltyState {
+    pub penalties: HashMap<u16, f32>,
+    pub penalty_free_tokens: Arc<HashSet<u16>>,
+}
+
+impl PenaltyState {
+    /// Accumulate penalties of tokens the model output earlier.
+    pub fn init(
+        &mut self,
+        params: &PenaltyParams,
+        model_tokens: &[u16],
+        penalty_free_tokens: Arc<HashSet<u16>>,
+    ) {
+        self.penalty_free_tokens = match &params.penalty_free_tokens {
+            Some(tokens) => Arc::new(tokens.iter().copied().collect()),
+            None => penalty_free_tokens,
+        };
+
+        for (index, token) in model_tokens.iter().rev().enumerate() {
+            let ap = params.presence_penalty;
+            let af = params.frequency_penalty;
+            let ad = params.penalty_decay;
+            let mut penalty = self.penalties.remove(token).unwrap_or(ap);
+            penalty += af * ad.powf(index as f32);
+            self.penalties.insert(*token, penalty);
+        }
+    }
+
+    /// Apply penalties to the raw model output.
+    pub fn transform(&self, output: &mut [f32]) {
+        self.penalties
+            .iter()
+            .filter(|(token, _)| !self.penalty_free_tokens.contains(token))
+            .for_each(|(token, penalty)| output[*token as usize] -= penalty)
+    }
+
+    /// Decay all penalties, and then penalize the token just sampled.
+    pub fn update(&mut self, params: &PenaltyParams, token: u16) {
+        self.penalties
+            .iter_mut()
+            .for_each(|(_, penalty)| *penalty *= params.penalty_decay);
+
+        let penalty = match self.penalties.get(&token) {
+            Some(penalty) => penalty + params.frequency_penalty,
+            None => params.presence_penalty,
+        };
+        self.penalties.insert(token, penalty);
+    }
+}
@@ -0,0 +1,58 @@
+use derivative::Derivative;
+use itertools::Itertools;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::truncate::{DrawParams, Truncate, TruncateSampler};
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+pub struct TailFreeParams {
+    /// Cumulative weight of the second derivatives of the sorted distribution to keep.
+    #[derivative(Default(value = "0.95"))]
+    pub tfs_z: f32,
+    #[serde(flatten)]
+    pub draw: DrawParams,
+}
+
+/// Cut off the tail of the distribution where its curvature flattens out.
+pub fn candidates(probs: &[f32], tfs_z: f32) -> Vec<(usize, f32)> {
+    let mut sorted = super::sort_probs(probs);
+    let first = sorted
+        .iter()
+        .tuple_windows()
+        .map(|((_, x), (_, y))| x - y)
+        .collect_vec();
+    let second = first
+        .iter()
+        .tuple_windows()
+        .map(|(x, y)| (x - y).abs())
+        .collect_vec();
+
+    let sum: f32 = second.iter().sum();
+    let len = match sum > 0.0 && tfs_z < 1.0 {
+        true => second
+            .iter()
+            .scan(0.0, |cum, x| {
+                *cum += x / sum;
+                Some(*cum)
+            })
+            .position(|cum| cum > tfs_z)
+            .unwrap_or(second.len()),
+        false => sorted.len(),
+    };
+    sorted.truncate(len.max(1));
+    sorted
+}
+
+impl Truncate for TailFreeParams {
+    fn draw(&self) -> &DrawParams {
+        &self.draw
+    }
+
+    fn candidates(&self, probs: &[f32]) -> Vec<(usize, f32)> {
+        candidates(probs, self.tfs_z)
+    }
+}
+
+pub type TailFreeSampler = TruncateSampler<TailFreeParams>;
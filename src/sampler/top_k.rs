@@ -0,0 +1,36 @@
+use derivative::Derivative;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::truncate::{DrawParams, Truncate, TruncateSampler};
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+pub struct TopKParams {
+    /// Number of the most likely tokens to sample from. All tokens are kept if this is 0.
+    #[derivative(Default(value = "40"))]
+    pub top_k: usize,
+    #[serde(flatten)]
+    pub draw: DrawParams,
+}
+
+/// Keep the `top_k` most likely tokens.
+pub fn candidates(probs: &[f32], top_k: usize) -> Vec<(usize, f32)> {
+    let mut sorted = super::sort_probs(probs);
+    if top_k > 0 {
+        sorted.truncate(top_k);
+    }
+    sorted
+}
+
+impl Truncate for TopKParams {
+    fn draw(&self) -> &DrawParams {
+        &self.draw
+    }
+
+    fn candidates(&self, probs: &[f32]) -> Vec<(usize, f32)> {
+        candidates(probs, self.top_k)
+    }
+}
+
+pub type TopKSampler = TruncateSampler<TopKParams>;
@@ -0,0 +1,88 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use derivative::Derivative;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    penalty::{PenaltyParams, PenaltyState},
+    Sampler,
+};
+
+/// Params shared by samplers that draw from the tokens kept by a truncation rule.
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+pub struct DrawParams {
+    #[derivative(Default(value = "1.0"))]
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    #[serde(flatten)]
+    pub penalty: PenaltyParams,
+    /// Seed of the random number generator. Results are reproducible if this is set.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+fn default_temperature() -> f32 {
+    DrawParams::default().temperature
+}
+
+/// A rule that keeps some of the tokens to draw from.
+pub trait Truncate {
+    fn draw(&self) -> &DrawParams;
+    /// Candidates of `(token, probability)` that are kept.
+    fn candidates(&self, probs: &[f32]) -> Vec<(usize, f32)>;
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TruncateState {
+    pub penalty: PenaltyState,
+    pub rng: fastrand::Rng,
+}
+
+/// Applies penalties and bias, and then draws from the candidates kept by the rule `P`.
+#[derive(Debug, Default, Clone)]
+pub struct TruncateSampler<P> {
+    pub params: P,
+    pub state: TruncateState,
+}
+
+impl<P: Truncate> TruncateSampler<P> {
+    pub fn new(params: P) -> Self {
+        let state = TruncateState {
+            rng: params
+                .draw()
+                .seed
+                .map(fastrand::Rng::with_seed)
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        Self { params, state }
+    }
+}
+
+impl<P: Truncate> Sampler for TruncateSampler<P> {
+    fn init(&mut self, model_tokens: &[u16], penalty_free_tokens: Arc<HashSet<u16>>) {
+        let TruncateSampler { params, state } = self;
+        state
+            .penalty
+            .init(&params.draw().penalty, model_tokens, penalty_free_tokens);
+    }
+
+    fn transform(&self, output: &mut [f32], bias: &HashMap<u16, f32>) {
+        self.state.penalty.transform(output);
+        super::apply_bias(output, bias);
+    }
+
+    fn sample(&mut self, probs: &[f32]) -> u16 {
+        let TruncateSampler { params, state } = self;
+        let candidates = params.candidates(probs);
+        let draw = params.draw();
+        let token = super::draw(&candidates, draw.temperature, &mut state.rng);
+        state.penalty.update(&draw.penalty, token);
+        token
+    }
+}
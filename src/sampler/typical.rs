@@ -0,0 +1,53 @@
+use derivative::Derivative;
+use itertools::Itertools;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::truncate::{DrawParams, Truncate, TruncateSampler};
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+pub struct TypicalParams {
+    /// Cumulative probability of tokens to keep, which are the closest to the expected information content.
+    #[derivative(Default(value = "0.95"))]
+    pub typical_p: f32,
+    #[serde(flatten)]
+    pub draw: DrawParams,
+}
+
+/// Keep tokens whose information content is the closest to the entropy, until their cumulative probability exceeds `typical_p`.
+pub fn candidates(probs: &[f32], typical_p: f32) -> Vec<(usize, f32)> {
+    let entropy: f32 = probs
+        .iter()
+        .filter(|&&x| x > 0.0)
+        .map(|&x| -x * x.ln())
+        .sum();
+    let surprise = |x: f32| (-x.ln() - entropy).abs();
+    probs
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, x)| *x > 0.0)
+        .sorted_unstable_by(|(_, x), (_, y)| surprise(*x).total_cmp(&surprise(*y)))
+        .scan(0.0, |cum, (id, x)| {
+            if *cum > typical_p {
+                None
+            } else {
+                *cum += x;
+                Some((id, x))
+            }
+        })
+        .collect_vec()
+}
+
+impl Truncate for TypicalParams {
+    fn draw(&self) -> &DrawParams {
+        &self.draw
+    }
+
+    fn candidates(&self, probs: &[f32]) -> Vec<(usize, f32)> {
+        candidates(probs, self.typical_p)
+    }
+}
+
+pub type TypicalSampler = TruncateSampler<TypicalParams>;
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use half::{bf16, f16};
+use itertools::Itertools;
+use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+use web_rwkv::{
+    model::{v4, v5, v6, ModelInfo},
+    tensor::shape::Shape,
+};
+
+/// Metadata entry that marks files written by [`to_safetensors`].
+const FORMAT_KEY: &str = "format";
+const STATE_FORMAT: &str = "state";
+
+/// Access to the tensors of a backed state, so that states are converted from and to safetensors without an intermediate copy.
+pub trait StateTensors: Clone {
+    /// Chunk size and head size of the state. States of v4 models hold no matrix-valued time state, so they have none.
+    fn layout(&self) -> Option<(usize, usize)>;
+    /// Drop the tensors that the state builder makes beyond those that back the layers of the model.
+    fn fit(&mut self, info: &ModelInfo);
+    /// Tensors of the state in order, each with its shape.
+    fn tensors(&self) -> Vec<(Shape, &[f32])>;
+    /// Mutable tensors of the state in order, each with its shape.
+    fn tensors_mut(&mut self) -> Vec<(Shape, &mut [f32])>;
+}
+
+impl StateTensors for v4::BackedState {
+    fn layout(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    fn fit(&mut self, _info: &ModelInfo) {}
+
+    fn tensors(&self) -> Vec<(Shape, &[f32])> {
+        vec![(self.shape, &self.data)]
+    }
+
+    fn tensors_mut(&mut self) -> Vec<(Shape, &mut [f32])> {
+        vec![(self.shape, &mut self.data)]
+    }
+}
+
+impl StateTensors for v5::BackedState {
+    fn layout(&self) -> Option<(usize, usize)> {
+        Some((self.chunk_size, self.head_size))
+    }
+
+    fn fit(&mut self, info: &ModelInfo) {
+        self.data.truncate(info.num_layer.div_ceil(self.chunk_size));
+    }
+
+    fn tensors(&self) -> Vec<(Shape, &[f32])> {
+        self.data
+            .iter()
+            .map(|(shape, x)| (*shape, &x[..]))
+            .collect()
+    }
+
+    fn tensors_mut(&mut self) -> Vec<(Shape, &mut [f32])> {
+        self.data
+            .iter_mut()
+            .map(|(shape, x)| (*shape, &mut x[..]))
+            .collect()
+    }
+}
+
+impl StateTensors for v6::BackedState {
+    fn layout(&self) -> Option<(usize, usize)> {
+        Some((self.chunk_size, self.head_size))
+    }
+
+    fn fit(&mut self, info: &ModelInfo) {
+        self.data.truncate(info.num_layer.div_ceil(self.chunk_size));
+    }
+
+    fn tensors(&self) -> Vec<(Shape, &[f32])> {
+        self.data
+            .iter()
+            .map(|(shape, x)| (*shape, &x[..]))
+            .collect()
+    }
+
+    fn tensors_mut(&mut self) -> Vec<(Shape, &mut [f32])> {
+        self.data
+            .iter_mut()
+            .map(|(shape, x)| (*shape, &mut x[..]))
+            .collect()
+    }
+}
+
+fn state_key(index: usize) -> String {
+    format!("state.{index}")
+}
+
+/// Shape of a state tensor as safetensors lists it, i.e., with the slowest dimension first.
+fn tensor_shape(shape: Shape) -> Vec<usize> {
+    shape.iter().rev().copied().collect()
+}
+
+/// Name of the tensor that holds the matrix-valued state of a layer in state-tuned checkpoints,
+/// which is of shape `[num_head, head_size, head_size]`.
+fn time_state_key(layer: usize) -> String {
+    format!("blocks.{layer}.att.time_state")
+}
+
+/// Convert a backed state into safetensors, with one tensor per tensor of the state,
+/// so that the state can be restored exactly by [`from_safetensors`].
+pub fn to_safetensors<B: StateTensors>(backed: &B) -> Result<Vec<u8>> {
+    let views: Vec<_> = backed
+        .tensors()
+        .into_iter()
+        .enumerate()
+        .map(|(index, (shape, data))| {
+            TensorView::new(Dtype::F32, tensor_shape(shape), bytemuck::cast_slice(data))
+                .map(|view| (state_key(index), view))
+        })
+        .try_collect()?;
+    let metadata = HashMap::from([(FORMAT_KEY.to_string(), STATE_FORMAT.to_string())]);
+    Ok(safetensors::serialize(views, &Some(metadata))?)
+}
+
+fn read_tensor(tensors: &SafeTensors, name: &str) -> Result<Vec<f32>> {
+    let tensor = tensors.tensor(name)?;
+    let data = tensor.data();
+    let data = match tensor.dtype() {
+        Dtype::F32 => data
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect(),
+        Dtype::F16 => data
+            .chunks_exact(2)
+            .map(|x| f16::from_le_bytes([x[0], x[1]]).to_f32())
+            .collect(),
+        Dtype::BF16 => data
+            .chunks_exact(2)
+            .map(|x| bf16::from_le_bytes([x[0], x[1]]).to_f32())
+            .collect(),
+        dtype => bail!("tensor {name} is of unsupported type {dtype:?}"),
+    };
+    Ok(data)
+}
+
+/// Fill the tensors of `backed` from a file written by [`to_safetensors`], which must hold tensors of the very same shapes.
+fn read_state<B: StateTensors>(tensors: &SafeTensors, mut backed: B) -> Result<B> {
+    let mut targets = backed.tensors_mut();
+    if tensors.len() != targets.len() {
+        bail!(
+            "state has {} tensors, but the model takes {}",
+            tensors.len(),
+            targets.len()
+        );
+    }
+    for (index, (shape, data)) in targets.iter_mut().enumerate() {
+        let name = state_key(index);
+        let tensor = tensors.tensor(&name)?;
+        let expected = tensor_shape(*shape);
+        if tensor.shape() != expected {
+            bail!(
+                "tensor {name} is of shape {:?}, but the model takes {:?}",
+                tensor.shape(),
+                expected
+            );
+        }
+        data.copy_from_slice(&read_tensor(tensors, &name)?);
+    }
+    drop(targets);
+    Ok(backed)
+}
+
+/// Fill `backed` with the `time_state` tensors of a state-tuned checkpoint.
+/// Each chunk of v5 and v6 states holds `head_size + 2` rows of `num_emb` floats per layer, where the time state takes
+/// rows `1..=head_size` indexed by key, with heads side by side in each row. The other rows, i.e., token shifts, are left as they are.
+fn read_time_state<B: StateTensors>(tensors: &SafeTensors, mut backed: B) -> Result<B> {
+    let num_layer = (0..)
+        .take_while(|layer| tensors.tensor(&time_state_key(*layer)).is_ok())
+        .count();
+    if num_layer == 0 {
+        bail!("missing state format in metadata");
+    }
+
+    let Some((chunk_size, head_size)) = backed.layout() else {
+        bail!("the model takes no time state");
+    };
+    let mut chunks = backed.tensors_mut();
+    if num_layer > chunk_size * chunks.len() {
+        bail!("time state has {num_layer} layers, more than the model");
+    }
+
+    for (layer, time_state) in (0..num_layer).map(|layer| (layer, time_state_key(layer))) {
+        let time_state = read_tensor(tensors, &time_state)?;
+        let (shape, data) = &mut chunks[layer / chunk_size];
+        let (num_emb, num_row) = (shape[0], shape[1]);
+        if time_state.len() != num_emb * head_size {
+            bail!("time state of layer {layer} does not fit the model");
+        }
+
+        let offset = (layer % chunk_size) * (head_size + 2) + 1;
+        for (batch, data) in data.chunks_exact_mut(num_row * num_emb).enumerate() {
+            for (index, x) in time_state.iter().enumerate() {
+                let (head, key, value) = (
+                    index / (head_size * head_size),
+                    index / head_size % head_size,
+                    index % head_size,
+                );
+                let row = offset + key;
+                let column = head * head_size + value;
+                let Some(y) = data.get_mut(row * num_emb + column) else {
+                    bail!("time state of layer {layer} does not fit batch {batch}");
+                };
+                *y = *x;
+            }
+        }
+    }
+    drop(chunks);
+    Ok(backed)
+}
+
+/// Restore a backed state from safetensors written by [`to_safetensors`] or from a state-tuned checkpoint.
+/// `reference` is a fresh state of the current model, which gives the layout; the state is rejected unless it fits.
+pub fn from_safetensors<B: StateTensors>(data: &[u8], reference: &B) -> Result<B> {
+    let (_, metadata) = SafeTensors::read_metadata(data)?;
+    let tensors = SafeTensors::deserialize(data)?;
+    let format = metadata
+        .metadata()
+        .as_ref()
+        .and_then(|metadata| metadata.get(FORMAT_KEY));
+    match format {
+        Some(format) if format == STATE_FORMAT => read_state(&tensors, reference.clone()),
+        _ => read_time_state(&tensors, reference.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD_SIZE: usize = 2;
+    const NUM_EMB: usize = 4;
+    const NUM_ROW: usize = 2 * (HEAD_SIZE + 2);
+
+    /// A v5 state of 3 layers in chunks of 2, with 2 heads of size 2, filled by `f`.
+    fn state(f: impl Fn(usize) -> f32) -> v5::BackedState {
+        let shape = Shape::new(NUM_EMB, NUM_ROW, 1, 1);
+        let data = (0..2)
+            .map(|chunk| {
+                let data = (0..NUM_EMB * NUM_ROW)
+                    .map(|index| f(chunk * NUM_EMB * NUM_ROW + index))
+                    .collect();
+                (shape, data)
+            })
+            .collect();
+        v5::BackedState {
+            num_batch: 1,
+            chunk_size: 2,
+            head_size: HEAD_SIZE,
+            data,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let backed = state(|index| index as f32 * 0.5 - 3.0);
+        let data = to_safetensors(&backed).unwrap();
+        let loaded = from_safetensors(&data, &state(|_| 0.0)).unwrap();
+        assert_eq!(loaded.data, backed.data);
+
+        let backed = v4::BackedState {
+            shape: Shape::new(3, 5, 1, 1),
+            data: (0..15).map(|x| x as f32).collect(),
+        };
+        let reference = v4::BackedState {
+            data: vec![0.0; 15],
+            ..backed.clone()
+        };
+        let data = to_safetensors(&backed).unwrap();
+        let loaded = from_safetensors(&data, &reference).unwrap();
+        assert_eq!(loaded.data, backed.data);
+    }
+
+    #[test]
+    fn other_shape() {
+        let data = to_safetensors(&state(|index| index as f32)).unwrap();
+
+        // fewer chunks
+        let mut reference = state(|_| 0.0);
+        reference.data.pop();
+        assert!(from_safetensors(&data, &reference).is_err());
+
+        // wider embedding
+        let mut reference = state(|_| 0.0);
+        for (shape, data) in reference.data.iter_mut() {
+            *shape = Shape::new(2 * NUM_EMB, NUM_ROW, 1, 1);
+            *data = vec![0.0; 2 * NUM_EMB * NUM_ROW];
+        }
+        assert!(from_safetensors(&data, &reference).is_err());
+
+        // another model version
+        let reference = v4::BackedState {
+            shape: Shape::new(NUM_EMB, NUM_ROW, 1, 1),
+            data: vec![0.0; NUM_EMB * NUM_ROW],
+        };
+        assert!(from_safetensors(&data, &reference).is_err());
+    }
+
+    #[test]
+    fn time_state() {
+        let num_layer = 3;
+        let len = NUM_EMB * HEAD_SIZE;
+        let tensors = (0..num_layer)
+            .map(|layer| {
+                let data: Vec<u8> = (0..len)
+                    .map(|index| (layer * len + index + 1) as f32)
+                    .flat_map(f32::to_le_bytes)
+                    .collect();
+                (time_state_key(layer), data)
+            })
+            .collect_vec();
+        let views = tensors
+            .iter()
+            .map(|(name, data)| {
+                let shape = vec![NUM_EMB / HEAD_SIZE, HEAD_SIZE, HEAD_SIZE];
+                (name, TensorView::new(Dtype::F32, shape, data).unwrap())
+            })
+            .collect_vec();
+        let data = safetensors::serialize(views, &None).unwrap();
+
+        let backed = from_safetensors(&data, &state(|_| 0.0)).unwrap();
+        for layer in 0..num_layer {
+            let (_, data) = &backed.data[layer / 2];
+            let offset = (layer % 2) * (HEAD_SIZE + 2);
+            for (head, key, value) in itertools::iproduct!(0..2, 0..HEAD_SIZE, 0..HEAD_SIZE) {
+                let index = (head * HEAD_SIZE + key) * HEAD_SIZE + value;
+                let row = offset + 1 + key;
+                let column = head * HEAD_SIZE + value;
+                assert_eq!(
+                    data[row * NUM_EMB + column],
+                    (layer * len + index + 1) as f32
+                );
+            }
+            // token shifts are untouched
+            for row in [offset, offset + HEAD_SIZE + 1] {
+                assert!(data[row * NUM_EMB..][..NUM_EMB].iter().all(|x| *x == 0.0));
+            }
+        }
+        // the half-used chunk keeps the rows of its missing layer
+        let (_, data) = &backed.data[1];
+        assert!(data[(HEAD_SIZE + 2) * NUM_EMB..].iter().all(|x| *x == 0.0));
+    }
+}